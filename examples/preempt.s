@@ -0,0 +1,47 @@
+    jmp cc #boot
+    jmp cc #tick
+
+boot:
+    cincoffset c1 dd 2048
+    csetbounds c1 c1 16
+    cspecialw idd c1
+    cspecialw ivec cc
+
+    candperm c2 cc 7
+    cspecialw ipcc c2
+    cincoffset c3 dd 2304
+    csetbounds c3 c3 256
+    cspecialw ipdd c3
+    store c1 2 #task_b
+
+    cincoffset c4 dd 4112
+    csetbounds c4 c4 4
+    store c4 2 20
+    ipcw #task_a
+    iret
+
+tick:
+    store dd 0 r0
+    load r0 dd 6
+    add r0 1
+    store dd 6 r0
+    cond r0 >= 8
+    halt 0
+    jmp cc #switch
+switch:
+    ipcr r0
+    store dd 4 r0
+    load r0 dd 2
+    ipcw r0
+    load r0 dd 4
+    store dd 2 r0
+    load r0 dd 0
+    iret
+
+task_a:
+    emit 97
+    jmp cc #task_a
+
+task_b:
+    emit 98
+    jmp cc #task_b
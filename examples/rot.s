@@ -0,0 +1,25 @@
+    emit 65
+    emit 10
+    mov r1 1
+    mov r2 16
+    mov r3 256
+    mov r4 4096
+    mov sp 4096
+loop:
+    mov r0 .
+    add r0 40
+    push r0
+    xor r0 r0
+    jmp cc #rot
+    jmp cc #loop
+rot:
+    push r1
+    push r2
+    push r3
+    push r4
+    pop r3
+    pop r2
+    pop r1
+    pop r4
+    pop r0
+    jmp cc r0
@@ -1,6 +1,86 @@
-pub type Int = i16;
+use std::mem::size_of;
+
+/// Wide enough for every `Width`; the machine keeps values in range.
+pub type Int = i64;
+
+/// The machine word: the size of registers, immediates and capability fields.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Width {
+    #[default]
+    W16,
+    W32,
+    W64,
+}
+
+impl Width {
+    pub const ALL: [Width; 3] = [Width::W16, Width::W32, Width::W64];
+
+    pub const fn bytes(self) -> usize {
+        match self {
+            Width::W16 => 2,
+            Width::W32 => 4,
+            Width::W64 => 8,
+        }
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    pub const fn min(self) -> Int {
+        Int::MIN >> (Int::BITS - self.bits())
+    }
+
+    pub const fn max(self) -> Int {
+        Int::MAX >> (Int::BITS - self.bits())
+    }
+
+    /// Saturates `n` to the range of a word.
+    pub fn clamp(self, n: Int) -> Int {
+        n.clamp(self.min(), self.max())
+    }
+
+    pub fn fits(self, n: Int) -> bool {
+        (self.min()..=self.max()).contains(&n)
+    }
+
+    pub const fn instr_size(self) -> usize {
+        4 + 2 * self.bytes()
+    }
+
+    /// A capability granule holds four words, and is never smaller than 16 bytes.
+    pub const fn cap_size(self) -> usize {
+        if self.bytes() * 4 > 16 { self.bytes() * 4 } else { 16 }
+    }
+
+    /// Writes the low `bytes()` bytes of `n`, little-endian.
+    pub fn write(self, n: Int, out: &mut [u8]) {
+        out[..self.bytes()].copy_from_slice(&n.to_le_bytes()[..self.bytes()]);
+    }
+
+    /// Reads a little-endian, sign-extended word.
+    pub fn read(self, bytes: &[u8]) -> Int {
+        let mut buf = [0; size_of::<Int>()];
+        buf[..self.bytes()].copy_from_slice(&bytes[..self.bytes()]);
+        let shift = Int::BITS - self.bits();
+        (Int::from_le_bytes(buf) << shift) >> shift
+    }
+
+    /// `n` as an unsigned word, for printing.
+    pub fn unsigned(self, n: Int) -> u64 {
+        n as u64 & (u64::MAX >> (u64::BITS - self.bits()))
+    }
+}
+
+impl TryFrom<u8> for Width {
+    type Error = u8;
+
+    /// Converts from a size in bytes.
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Width::ALL.into_iter().find(|width| width.bytes() == value as usize).ok_or(value)
+    }
+}
 
-#[repr(align(4))]
 #[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     Mov(GpRegister, Value),
@@ -25,20 +105,44 @@ pub enum Instruction {
     Cond(GpRegister, Condition, Value),
 
     Emit(Value),
-
-    // CLoad(GpRegister, CRegister),
-    // CStore(Value, CRegister),
-
-    // CLoadCap(CRegister, CRegister),
-    // CStoreCap(CRegister, CRegister),
-    // CJmp(CRegister),
-
-    // CPushCap(CRegister),
-    // CPopCap(CRegister),
-
-    // CInvoke(CRegister, CRegister),
-
-    // CRestrict(CRegister, Value),
+    Input(GpRegister),
+    Halt(Value),
+
+    CReadSpecial(CRegister, SpecialRegister),
+    CWriteSpecial(SpecialRegister, CRegister),
+    Cause(GpRegister),
+    TRet,
+
+    Ei,
+    Di,
+    IRet,
+    IpcRead(GpRegister),
+    IpcWrite(Value),
+
+    CLoad(GpRegister, CRegister),
+    CStore(Value, CRegister),
+
+    CLoadCap(CRegister, CRegister, Value),
+    CStoreCap(CRegister, Value, CRegister),
+    CJmp(CRegister),
+
+    CPushCap(CRegister),
+    CPopCap(CRegister),
+
+    CInvoke(CRegister, CRegister),
+    /// Calls into another compartment through a sealed code/data pair.
+    /// The callee gets the unsealed pair as `CC` and `DD`, and its
+    /// arguments in `R0`-`R3` and `C2`-`C3`; every other register is cleared.
+    CCall(CRegister, CRegister),
+    /// Returns from a `CCall` with results in `R0`-`R3` and `C2`-`C3`. The
+    /// caller's other registers are put back as they were.
+    CReturn,
+    CSeal(CRegister, CRegister),
+    CUnseal(CRegister, CRegister),
+
+    CSetBounds(CRegister, CRegister, Value),
+    CAndPerm(CRegister, CRegister, Value),
+    CIncOffset(CRegister, CRegister, Value),
 }
 
 #[repr(u8)]
@@ -69,6 +173,23 @@ pub enum Value {
     Imm(Int),
 }
 
+macro_rules! from_index {
+    ($ty:ident, $($variant:ident),*) => {
+        impl TryFrom<u8> for $ty {
+            type Error = u8;
+
+            fn try_from(value: u8) -> Result<Self, u8> {
+                [$($ty::$variant),*].get(value as usize).copied().ok_or(value)
+            }
+        }
+    };
+}
+
+from_index!(Condition, L, LE, E, GE, G);
+from_index!(GpRegister, R0, R1, R2, R3, R4, R5, R6, SP);
+from_index!(CRegister, C0, C1, C2, C3, C4, C5, CC, DD);
+from_index!(SpecialRegister, Tcc, Epcc, Ecap, Ivec, Idd, Ipcc, Ipdd);
+
 pub const GP_REGISTERS: usize = 8;
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
@@ -83,6 +204,27 @@ pub enum GpRegister {
     SP,
 }
 
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+pub enum SpecialRegister {
+    /// Trap handler capability, jumped to on a fault
+    Tcc,
+    /// `CC` at the time of the fault, pointing at the faulting instruction
+    Epcc,
+    /// The capability that caused the fault, if any
+    Ecap,
+    /// Interrupt vector table; interrupt n enters at PC n instructions in
+    Ivec,
+    /// Installed as `DD` on interrupt entry
+    Idd,
+    /// `CC` at the time of the interrupt; the PC is saved beside it
+    Ipcc,
+    /// `DD` at the time of the interrupt
+    Ipdd,
+}
+
+pub const SPECIAL_REGISTERS: usize = 7;
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy)]
 pub enum CRegister {
@@ -1,18 +1,32 @@
 use std::{ops::Range, num::NonZeroU8, fmt::{Display, Debug}};
 
-use crate::bytecode::Int;
+use crate::{bytecode::{Int, Width}, concentrate::Concentrate};
 
+#[derive(Clone, Copy)]
 pub struct Permissions {
     pub read: bool,
     pub write: bool,
-    pub exec: bool
+    pub exec: bool,
+    pub seal: bool,
+    pub unseal: bool,
+    /// Lets code running under it touch interrupt and trap state
+    pub privileged: bool,
 }
 
 impl Permissions {
     const NULL: Permissions = Self::rwx(false, false, false);
 
     pub const fn rwx(read: bool, write: bool, exec: bool) -> Self {
-        Self { read, write, exec }
+        Self { read, write, exec, seal: false, unseal: false, privileged: false }
+    }
+
+    /// Everything but sealing, as the machine's memory root holds.
+    pub const fn root() -> Self {
+        Self { privileged: true, ..Self::rwx(true, true, true) }
+    }
+
+    pub const fn sealing(seal: bool, unseal: bool) -> Self {
+        Self { seal, unseal, ..Self::NULL }
     }
 }
 
@@ -21,6 +35,9 @@ impl From<Permissions> for u8 {
         value.read as u8
         | (value.write as u8) << 1
         | (value.exec as u8) << 2
+        | (value.seal as u8) << 3
+        | (value.unseal as u8) << 4
+        | (value.privileged as u8) << 5
     }
 }
 
@@ -29,7 +46,10 @@ impl From<u8> for Permissions {
         Self {
             read: value & 1 != 0,
             write: value & 2 != 0,
-            exec: value & 4 != 0
+            exec: value & 4 != 0,
+            seal: value & 8 != 0,
+            unseal: value & 16 != 0,
+            privileged: value & 32 != 0,
         }
     }
 }
@@ -39,11 +59,14 @@ impl Display for Permissions {
         let r = if self.read { 'r' } else { '-' };
         let w = if self.write { 'w' } else { '-' };
         let x = if self.exec { 'x' } else { '-' };
-        write!(f, "{r}{w}{x}")
+        let s = if self.seal { 's' } else { '-' };
+        let u = if self.unseal { 'u' } else { '-' };
+        let p = if self.privileged { 'p' } else { '-' };
+        write!(f, "{r}{w}{x}{s}{u}{p}")
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Seal {
     Sealed(NonZeroU8),
     Unsealed
@@ -67,10 +90,48 @@ impl From<u8> for Seal {
     }
 }
 
-pub const CAP_SIZE: usize = 16;
+/// How a machine represents capability bounds.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum CapFormat {
+    /// Both bounds stored in full, so any range is exact
+    #[default]
+    Full,
+    /// Bounds compressed relative to the pointer, losing precision
+    Compressed(Concentrate),
+}
+
+impl CapFormat {
+    pub fn compressed(width: Width) -> Self {
+        CapFormat::Compressed(Concentrate::new(width))
+    }
 
-#[derive(Clone, Copy)]
-#[repr(align(4))]
+    /// Bytes a capability takes in memory: four words in full, or a pointer
+    /// word and a 64-bit metadata word when compressed.
+    pub fn cap_size(self, width: Width) -> usize {
+        match self {
+            CapFormat::Full => width.cap_size(),
+            CapFormat::Compressed(_) => (width.bytes() + 8).next_power_of_two(),
+        }
+    }
+
+    /// The bounds actually granted for a request of `bounds`.
+    pub fn round(self, bounds: Range<Int>) -> Range<Int> {
+        match self {
+            CapFormat::Full => bounds,
+            CapFormat::Compressed(c) => c.round(bounds),
+        }
+    }
+
+    /// The bounds `inner` decodes to once its pointer has moved to `ptr`.
+    pub fn rebase(self, inner: Inner, ptr: Int) -> Range<Int> {
+        match self {
+            CapFormat::Full => inner.bounds(),
+            CapFormat::Compressed(c) => c.decode(c.encode(inner.bounds()), ptr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub struct Inner {
     ptr: Int,
     start: Int,
@@ -99,6 +160,10 @@ impl Inner {
         Seal::from((self.meta >> 8) as u8)
     }
 
+    pub fn set_seal(&mut self, seal: Seal) {
+        *self = Self::new(self.ptr, self.bounds(), self.perms(), seal);
+    }
+
     pub fn bounds(&self) -> Range<Int> {
         self.start..self.end
     }
@@ -110,6 +175,41 @@ impl Inner {
     pub fn in_range(&self) -> bool {
         self.bounds().contains(&self.ptr())
     }
+
+    /// The in-memory form. In full, ptr, start, end and meta are consecutive
+    /// words; compressed, the pointer word is followed by the metadata word.
+    pub fn to_bytes(self, format: CapFormat, width: Width) -> Vec<u8> {
+        match format {
+            CapFormat::Full => {
+                let mut out = vec![0; 4 * width.bytes()];
+                for (n, field) in [self.ptr, self.start, self.end, self.meta].into_iter().enumerate() {
+                    width.write(field, &mut out[n * width.bytes()..]);
+                }
+                out
+            },
+            CapFormat::Compressed(c) => {
+                let mut out = vec![0; width.bytes()];
+                width.write(self.ptr, &mut out);
+                let meta = c.pack(c.encode(self.bounds()), self.meta as u16);
+                out.extend_from_slice(&meta.to_le_bytes());
+                out
+            },
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], format: CapFormat, width: Width) -> Self {
+        let field = |n: usize| width.read(&bytes[n * width.bytes()..]);
+        match format {
+            CapFormat::Full => Self { ptr: field(0), start: field(1), end: field(2), meta: field(3) },
+            CapFormat::Compressed(c) => {
+                let ptr = field(0);
+                let word = u64::from_le_bytes(bytes[width.bytes()..width.bytes() + 8].try_into().unwrap());
+                let (compressed, meta) = c.unpack(word);
+                let bounds = c.decode(compressed, ptr);
+                Self { ptr, start: bounds.start, end: bounds.end, meta: meta as Int }
+            },
+        }
+    }
 }
 
 impl Default for Inner {
@@ -123,12 +223,63 @@ impl Default for Inner {
     }
 }
 
-#[derive(Default, Clone, Copy)]
+#[derive(Default, Clone, Copy, PartialEq)]
 pub struct Capability {
     pub inner: Inner,
     pub valid: bool,
 }
 
+// Derivations can only ever shrink authority: a result that would widen
+// its source, or that modifies a sealed capability, comes back with the
+// valid tag cleared. Under a compressed format the same goes for bounds
+// that round past the source's, or a pointer the bounds can't follow.
+impl Capability {
+    pub fn is_sealed(&self) -> bool {
+        matches!(self.inner.seal(), Seal::Sealed(_))
+    }
+
+    pub fn set_bounds(self, len: Int, format: CapFormat) -> Self {
+        let ptr = self.inner.ptr();
+        let old = self.inner.bounds();
+        let end = ptr.checked_add(len).unwrap_or(Int::MAX);
+        let new = format.round(ptr..end);
+        let narrower = len >= 0 && self.inner.in_range() && old.start <= new.start && new.end <= old.end;
+
+        Self {
+            inner: Inner::new(ptr, new, self.inner.perms(), self.inner.seal()),
+            valid: self.valid && !self.is_sealed() && narrower,
+        }
+    }
+
+    pub fn and_perms(self, mask: u8) -> Self {
+        let perms = u8::from(self.inner.perms()) & mask;
+
+        Self {
+            inner: Inner::new(self.inner.ptr(), self.inner.bounds(), perms.into(), self.inner.seal()),
+            valid: self.valid && !self.is_sealed(),
+        }
+    }
+
+    pub fn set_addr(mut self, addr: Int, format: CapFormat) -> Self {
+        let bounds = format.rebase(self.inner, addr);
+        let representable = bounds == self.inner.bounds();
+
+        self.inner = Inner::new(addr, bounds, self.inner.perms(), self.inner.seal());
+        self.valid &= !self.is_sealed() && representable;
+        self
+    }
+
+    pub fn sealed(mut self, otype: NonZeroU8) -> Self {
+        self.inner.set_seal(Seal::Sealed(otype));
+        self
+    }
+
+    pub fn unsealed(mut self) -> Self {
+        self.inner.set_seal(Seal::Unsealed);
+        self
+    }
+}
+
 impl Debug for Capability {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let c = if self.valid { '*' } else { ' ' };
@@ -0,0 +1,386 @@
+use std::{collections::HashMap, fs::{self, File}, io, path::Path, thread, time::Duration, process::ExitCode};
+
+use crate::{bytecode::{CRegister, Int, Width}, compile, console::{BufferConsole, FileConsole, StdoutConsole}, debugger::Debugger, disasm, image::Image, link::{self, Module}, loader::{self, Segments}, ir::Statement, diagnostic::Diagnostic, object::Object, parse, preprocess, snapshot, trace::{Format, Tracer}, vm::{Config, Machine, RunOutcome}};
+
+const USAGE: &str = "\
+usage: cap-emu <command> <file>... [options]
+
+commands:
+    asm <file> -o <out>    assemble a source file to a binary image
+    obj <file> -o <out>    assemble one module to an object file for `link`
+    link <file[@base]>... -o <out>
+                           link sources and objects into an image, each at
+                           base if given, else after the one before
+    run <file>             execute a source file, image or snapshot until it
+                           halts
+    disasm <file>          print a source file or image as assembly
+    trace <file>           execute, recording what each instruction changes
+    debug <file>           step through a program interactively
+
+run, disasm, trace and debug link several files first, as `link` would.
+
+options:
+    -o, --output <path>    where `asm`, `obj` or `link` write, or where a running
+                           program's output goes instead of stdout
+    -i, --input <path>     what `input` reads instead of stdin
+    -n, --ticks <n>        stop after n ticks
+    -d, --delay <ms>       sleep between ticks
+    -r, --regs             dump the registers after every tick
+    -w, --width <bits>     word width of 16, 32 or 64 bits (default 16)
+    -m, --memory <bytes>   size of memory (default 4096)
+    -c, --compressed       use compressed capabilities with rounded bounds
+    -f, --format <fmt>     trace as text, json (JSON Lines) or binary
+    -t, --trace-file <path>  where `trace` writes instead of stdout
+    -s, --snapshot <path>  save the machine here when `run` or `trace` stops
+    -l, --least-privilege  run on per-segment capabilities instead of the root
+    --data <bytes>         size of the zeroed data segment (implies -l)
+    --stack <bytes>        size of the stack segment (implies -l, default 512)
+";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Command {
+    Asm,
+    Obj,
+    Link,
+    Run,
+    Disasm,
+    Trace,
+    Debug,
+}
+
+struct Options {
+    command: Command,
+    /// Only `link` takes more than one
+    inputs: Vec<String>,
+    output: Option<String>,
+    input_file: Option<String>,
+    ticks: Option<u64>,
+    delay: Option<Duration>,
+    regs: bool,
+    width: Option<Width>,
+    memory: Option<usize>,
+    compressed: bool,
+    format: Format,
+    trace_file: Option<String>,
+    snapshot: Option<String>,
+    /// Set when the loader should drop the root capability
+    segments: Option<Segments>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let command = match args.next().as_deref() {
+        Some("asm") => Command::Asm,
+        Some("obj") => Command::Obj,
+        Some("link") => Command::Link,
+        Some("run") => Command::Run,
+        Some("disasm") => Command::Disasm,
+        Some("trace") => Command::Trace,
+        Some("debug") => Command::Debug,
+        Some(other) => return Err(format!("unknown command `{other}`")),
+        None => return Err("missing command".into()),
+    };
+
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut input_file = None;
+    let mut ticks = None;
+    let mut delay = None;
+    let mut regs = false;
+    let mut width = None;
+    let mut memory = None;
+    let mut compressed = false;
+    let mut format = Format::Text;
+    let mut trace_file = None;
+    let mut snapshot = None;
+    let mut segments: Option<Segments> = None;
+
+    while let Some(arg) = args.next() {
+        let mut operand = |name: &str| args.next().ok_or_else(|| format!("{name} needs a value"));
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(operand(&arg)?),
+            "-i" | "--input" => input_file = Some(operand(&arg)?),
+            "-n" | "--ticks" => ticks = Some(operand(&arg)?.parse().map_err(|e| format!("{arg}: {e}"))?),
+            "-d" | "--delay" => {
+                let ms = operand(&arg)?.parse().map_err(|e| format!("{arg}: {e}"))?;
+                delay = Some(Duration::from_millis(ms));
+            },
+            "-r" | "--regs" => regs = true,
+            "-c" | "--compressed" => compressed = true,
+            "-f" | "--format" => format = match operand(&arg)?.as_str() {
+                "text" => Format::Text,
+                "json" => Format::Json,
+                "binary" => Format::Binary,
+                other => return Err(format!("{arg}: unknown trace format `{other}`")),
+            },
+            "-t" | "--trace-file" => trace_file = Some(operand(&arg)?),
+            "-s" | "--snapshot" => snapshot = Some(operand(&arg)?),
+            "-l" | "--least-privilege" => _ = segments.get_or_insert_default(),
+            "--data" => segments.get_or_insert_default().data = operand(&arg)?.parse().map_err(|e| format!("{arg}: {e}"))?,
+            "--stack" => segments.get_or_insert_default().stack = operand(&arg)?.parse().map_err(|e| format!("{arg}: {e}"))?,
+            "-w" | "--width" => width = Some(match operand(&arg)?.as_str() {
+                "16" => Width::W16,
+                "32" => Width::W32,
+                "64" => Width::W64,
+                other => return Err(format!("{arg}: unsupported width `{other}`")),
+            }),
+            "-m" | "--memory" => memory = Some(operand(&arg)?.parse().map_err(|e| format!("{arg}: {e}"))?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{arg}`")),
+            _ if inputs.is_empty() || !matches!(command, Command::Asm | Command::Obj) => inputs.push(arg),
+            _ => return Err(format!("unexpected argument `{arg}`")),
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err("missing input file".into())
+    }
+    let name = match command {
+        Command::Asm => Some("asm"),
+        Command::Obj => Some("obj"),
+        Command::Link => Some("link"),
+        _ => None,
+    };
+    if let Some(name) = name.filter(|_| output.is_none()) {
+        return Err(format!("{name} needs an output path"))
+    }
+
+    Ok(Options { command, inputs, output, input_file, ticks, delay, regs, width, memory, compressed, format, trace_file, snapshot, segments })
+}
+
+/// Prints `errors` and sums them up in one line for the caller to report.
+fn report(path: &str, errors: Vec<Diagnostic>) -> String {
+    for error in &errors {
+        eprintln!("{error}");
+    }
+    match errors.len() {
+        1 => format!("could not assemble {path} due to the error above"),
+        n => format!("could not assemble {path} due to {n} errors"),
+    }
+}
+
+/// Expands includes and macros, then parses. Warnings are printed here.
+fn parse_source(path: &str, data: Vec<u8>) -> Result<Vec<Statement>, String> {
+    let source = String::from_utf8(data).map_err(|e| format!("{path}: {e}"))?;
+    let lines = preprocess::expand(&source, Path::new(path)).map_err(|e| report(path, e))?;
+    let statements = parse::parse(&lines).map_err(|e| report(path, e))?;
+
+    for warning in compile::warnings(&statements) {
+        eprintln!("{warning}");
+    }
+    Ok(statements)
+}
+
+/// Reads `data` as an image if it carries the image magic, otherwise
+/// assembles it as source for the requested width. Only source files come
+/// with labels.
+fn load(path: &str, data: Vec<u8>, width: Option<Width>) -> Result<(Image, HashMap<String, Int>), String> {
+    if Object::is_object(&data) {
+        return Err(format!("{path}: an object file has to be linked first"))
+    }
+
+    if Image::is_image(&data) {
+        let image = Image::read(&data).map_err(|e| format!("{path}: {e}"))?;
+        if width.is_some_and(|width| width != image.width) {
+            return Err(format!("{path}: image was built for {}-bit words", image.width.bits()))
+        }
+        return Ok((image, HashMap::new()))
+    }
+
+    let width = width.unwrap_or_default();
+    let ir = parse_source(path, data)?;
+    let labels = compile::layout(&ir, width);
+    let image = compile::compile(ir, width).map_err(|e| report(path, e))?;
+
+    Ok((image, labels))
+}
+
+/// Reads `data` as an object if it carries the object magic, otherwise
+/// assembles it as a module.
+fn object(path: &str, data: Vec<u8>, width: Width) -> Result<Object, String> {
+    if Object::is_object(&data) {
+        return Object::read(&data).map_err(|e| format!("{path}: {e}"))
+    }
+
+    let ir = parse_source(path, data)?;
+    compile::assemble(ir, width).map_err(|e| report(path, e))
+}
+
+/// Links every input, each written `path` or `path@base`.
+fn link_inputs(opts: &Options) -> Result<(Image, HashMap<String, Int>), String> {
+    let width = opts.width.unwrap_or_default();
+    let mut modules = Vec::new();
+    for input in &opts.inputs {
+        let (path, base) = match input.split_once('@') {
+            Some((path, base)) => {
+                let parsed = match base.strip_prefix("0x") {
+                    Some(hex) => usize::from_str_radix(hex, 16),
+                    None => base.parse(),
+                };
+                (path, Some(parsed.map_err(|e| format!("{input}: bad base address: {e}"))?))
+            },
+            None => (input.as_str(), None),
+        };
+
+        let data = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+        modules.push(Module { name: path.to_string(), object: object(path, data, width)?, base });
+    }
+
+    link::link(&modules, width).map_err(|e| e.to_string())
+}
+
+fn boot(image: &Image, opts: &Options) -> Result<Machine, String> {
+    let mut config = Config { width: image.width, compressed: opts.compressed, ..Default::default() };
+    if let Some(memory) = opts.memory {
+        config.memory_size = memory;
+    }
+
+    let mut machine = Machine::new(config).map_err(|e| e.to_string())?;
+    match opts.segments {
+        Some(segments) => loader::load(&mut machine, image, segments).map_err(|e| format!("loading image: {e}"))?,
+        None => {
+            let root = machine.reg[CRegister::DD];
+            machine.memory.store_slice(root, 0, &image.bytes).map_err(|e| format!("loading image: {e}"))?;
+            loader::grant(&mut machine.memory, root, root.inner.perms(), image).map_err(|e| format!("loading image: {e}"))?;
+        },
+    }
+    Ok(machine)
+}
+
+/// Picks a machine back up from a snapshot, which keeps its own shape.
+fn restore(path: &str, data: &[u8], opts: &Options) -> Result<Machine, String> {
+    let machine = snapshot::read(data).map_err(|e| format!("{path}: {e}"))?;
+    if opts.width.is_some_and(|width| width != machine.width()) {
+        return Err(format!("{path}: snapshot was taken with {}-bit words", machine.width().bits()))
+    }
+    Ok(machine)
+}
+
+/// Runs the machine until it halts, exiting with the program's exit code.
+fn execute(mut machine: Machine, opts: &Options) -> Result<ExitCode, String> {
+    let input: Box<dyn io::Read> = match &opts.input_file {
+        Some(path) => Box::new(File::open(path).map_err(|e| format!("{path}: {e}"))?),
+        None => Box::new(io::stdin()),
+    };
+    match &opts.output {
+        Some(path) => machine.set_console(FileConsole::new(File::create(path).map_err(|e| format!("{path}: {e}"))?, input)),
+        None => machine.set_console(StdoutConsole::new(input)),
+    }
+
+    if opts.command == Command::Trace {
+        let out: Box<dyn io::Write> = match &opts.trace_file {
+            Some(path) => Box::new(io::BufWriter::new(File::create(path).map_err(|e| format!("{path}: {e}"))?)),
+            None => Box::new(io::stdout()),
+        };
+        machine.set_tracer(Tracer::new(opts.format, machine.width(), out));
+    }
+
+    let mut tick = 0;
+    let outcome = loop {
+        if opts.ticks.is_some_and(|limit| tick >= limit) {
+            break RunOutcome::Exhausted
+        }
+
+        let outcome = machine.run(Some(1));
+        tick += 1;
+
+        if opts.regs {
+            println!("{:#}", machine.reg);
+        }
+
+        if !matches!(outcome, RunOutcome::Exhausted) {
+            break outcome
+        }
+
+        if let Some(delay) = opts.delay {
+            thread::sleep(delay);
+        }
+    };
+
+    if let Some(tracer) = machine.take_tracer() {
+        tracer.finish().map_err(|e| format!("writing trace: {e}"))?;
+    }
+
+    if let Some(path) = &opts.snapshot {
+        fs::write(path, snapshot::write(&machine)).map_err(|e| format!("{path}: {e}"))?;
+    }
+
+    match outcome {
+        RunOutcome::Halted(code) => Ok(ExitCode::from(code as u8)),
+        RunOutcome::Faulted { error, pc } => Err(format!("fault at pc {pc:04x}: {error}")),
+        RunOutcome::Exhausted => Err(format!("no halt after {tick} ticks")),
+    }
+}
+
+fn debug(mut machine: Machine, labels: HashMap<String, Int>, opts: &Options) -> Result<ExitCode, String> {
+    // stdin belongs to the debugger, so the program's console is a buffer
+    let input = match &opts.input_file {
+        Some(path) => fs::read(path).map_err(|e| format!("{path}: {e}"))?,
+        None => Vec::new(),
+    };
+    let console = BufferConsole::new(&input);
+    let output = console.output();
+    machine.set_console(console);
+
+    let mut debugger = Debugger::new(machine, labels, output);
+    debugger.repl(io::stdin().lock()).map_err(|e| e.to_string())?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn dispatch(opts: &Options) -> Result<ExitCode, String> {
+    let path = &opts.inputs[0];
+    let output = opts.output.as_deref().unwrap_or_default();
+
+    let (image, labels) = if opts.inputs.len() > 1 || opts.command == Command::Link {
+        link_inputs(opts)?
+    } else {
+        let data = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+
+        if snapshot::is_snapshot(&data) {
+            let machine = restore(path, &data, opts)?;
+            return match opts.command {
+                Command::Run | Command::Trace => execute(machine, opts),
+                Command::Debug => debug(machine, HashMap::new(), opts),
+                _ => Err(format!("{path}: a snapshot can only be run, traced or debugged")),
+            }
+        }
+
+        if opts.command == Command::Obj {
+            let object = object(path, data, opts.width.unwrap_or_default())?;
+            fs::write(output, object.write()).map_err(|e| format!("{output}: {e}"))?;
+            return Ok(ExitCode::SUCCESS)
+        }
+
+        load(path, data, opts.width)?
+    };
+
+    match opts.command {
+        Command::Asm | Command::Link => {
+            fs::write(output, image.write()).map_err(|e| format!("{output}: {e}"))?;
+            Ok(ExitCode::SUCCESS)
+        },
+        Command::Obj => unreachable!("objects are written above"),
+        Command::Disasm => {
+            print!("{}", disasm::disassemble_bytes(&image.bytes, &image.grants, image.width));
+            Ok(ExitCode::SUCCESS)
+        },
+        Command::Run | Command::Trace => execute(boot(&image, opts)?, opts),
+        Command::Debug => debug(boot(&image, opts)?, labels, opts),
+    }
+}
+
+pub fn main(args: impl Iterator<Item = String>) -> ExitCode {
+    let opts = match parse_args(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            return ExitCode::from(2)
+        },
+    };
+
+    match dispatch(&opts) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        },
+    }
+}
@@ -1,31 +1,266 @@
-use std::{collections::HashMap, mem::size_of};
+use std::{cell::RefCell, collections::HashMap};
 
-use crate::{bytecode::{Instruction, Int}, ir::{InterRep, Env}};
+use crate::{bytecode::{Int, Value, Width}, diagnostic::{Diagnostic, Location}, encoding, image::{Grant, Image}, ir::{CapLiteral, Convert, ConvertError, InterRep, InterRepValue, Env, Origin, Statement}, object::{Object, Reloc, Target}};
 
-#[derive(Debug)]
-pub enum CompileError {
-    UndefinedLabel(String)
+/// Points an undefined label at its use in the statement at `at`, and
+/// anything else at the statement.
+fn convert_error(at: &Location, error: ConvertError) -> Diagnostic {
+    match error {
+        ConvertError::UndefinedLabel(name) => undefined(at, &name),
+        ConvertError::UndefinedConstant(ref name) => {
+            let column = find(at, name).unwrap_or(at.column);
+            Diagnostic::error(at.line.at(column), error.to_string())
+        },
+        error => Diagnostic::error(at.clone(), error.to_string()),
+    }
+}
+
+/// Points at the use of label `name` in the statement at `at`, or at the
+/// statement itself if it can't be found.
+fn undefined(at: &Location, name: &str) -> Diagnostic {
+    let column = find(at, &format!("#{name}")).unwrap_or(at.column);
+    Diagnostic::error(at.line.at(column), format!("undefined label `{name}`"))
+}
+
+/// The column of `reference` as a whole word in the statement at `at`.
+fn find(at: &Location, reference: &str) -> Option<usize> {
+    let text = &at.line.text;
+    let is_ident = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    text.match_indices(reference)
+        .find(|(n, _)| {
+            *n + 1 >= at.column
+                && !text[n + reference.len()..].starts_with(is_ident)
+                && !text[..*n].ends_with(is_ident)
+        })
+        .map(|(n, _)| n + 1)
+}
+
+/// Bytes `ir` takes up when it starts at `offset`.
+fn size(ir: &InterRep, offset: usize, width: Width) -> usize {
+    match ir {
+        InterRep::Instruction(_) => width.instr_size(),
+        InterRep::Bytes(bytes) => bytes.len(),
+        InterRep::Words(words) => words.len() * width.bytes(),
+        InterRep::Align(n) => offset.next_multiple_of((*n).max(1)) - offset,
+        InterRep::Cap(..) => width.cap_size(),
+        InterRep::Label(_) | InterRep::Export(_) | InterRep::Import(_) | InterRep::Equ(..) => 0,
+    }
 }
 
-pub fn compile(inter_rep: Vec<InterRep>) -> Result<Vec<Instruction>, CompileError> {
+/// Assigns each label the offset of whatever follows it.
+pub fn layout(statements: &[Statement], width: Width) -> HashMap<String, Int> {
     let mut labels: HashMap<String, Int> = HashMap::new();
+    let mut offset = 0;
 
-    let mut instrs = Vec::new();
+    for Statement { ir, .. } in statements {
+        if let InterRep::Label(name) = ir {
+            labels.insert(name.clone(), offset.try_into().unwrap());
+        }
+        offset += size(ir, offset, width);
+    }
+
+    labels
+}
+
+/// Labels defined more than once. The last definition is the one used.
+pub fn warnings(statements: &[Statement]) -> Vec<Diagnostic> {
+    let mut defined: HashMap<&str, &Location> = HashMap::new();
+    let mut warnings = Vec::new();
+
+    for Statement { ir, at } in statements {
+        if let InterRep::Label(name) = ir {
+            if let Some(previous) = defined.insert(name, at) {
+                let note = format!("previously defined at {}:{}", previous.line.file, previous.line.number);
+                warnings.push(Diagnostic::warning(at.clone(), format!("label `{name}` is defined more than once")).with_note(note));
+            }
+        }
+    }
+
+    warnings
+}
+
+/// Works out every `.equ` constant in order, so each can use the ones
+/// before it. None may depend on where the module is placed.
+fn constants(statements: &[Statement], labels: &HashMap<String, Int>, width: Width) -> (HashMap<String, Int>, Vec<Diagnostic>) {
+    let mut constants = HashMap::new();
+    let mut errors = Vec::new();
+    let mut offset = 0;
+
+    for Statement { ir, at } in statements {
+        if let InterRep::Equ(name, value) = ir {
+            let origins = RefCell::new(Vec::new());
+            let env = Env { map: labels, constants: &constants, imports: &[], width, position: offset as Int, origins: &origins };
+            // a constant in error still counts as defined, so its uses
+            // don't report it again
+            let n = match value.convert(&env) {
+                Ok(Value::Imm(n)) if origins.borrow()[..] == [Origin::Absolute] => n,
+                Ok(_) => {
+                    errors.push(Diagnostic::error(at.clone(), format!("`{name}` depends on where the program is placed")));
+                    0
+                },
+                Err(e) => {
+                    errors.push(convert_error(at, e));
+                    0
+                },
+            };
+            constants.insert(name.clone(), n);
+        }
+        offset += size(ir, offset, width);
+    }
+
+    (constants, errors)
+}
+
+#[derive(Default)]
+struct Emitted {
+    code: Vec<u8>,
+    /// The offset of every word, and what its value came from
+    placed: Vec<(usize, Origin)>,
+    grants: Vec<Grant>,
+}
+
+impl Emitted {
+    /// Writes `value` into the word at `env.position`, which the code
+    /// already has room for.
+    fn word(&mut self, value: &InterRepValue, env: Env, at: &Location) -> Result<(), Diagnostic> {
+        let (position, width) = (env.position as usize, env.width);
+        let value = match value.convert(&env) {
+            Ok(Value::Imm(value)) => value,
+            Ok(Value::Reg(_)) => unreachable!("the parser only accepts constants for words"),
+            Err(e) => return Err(convert_error(at, e)),
+        };
+        if !width.fits(value) {
+            return Err(Diagnostic::error(at.clone(), format!("{value} doesn't fit in a {}-byte word", width.bytes())))
+        }
+
+        width.write(value, &mut self.code[position..]);
+        self.placed.push((position, env.origins.borrow().last().cloned().unwrap()));
+        Ok(())
+    }
+}
+
+/// Encodes the program, with `imports` standing in as 0. Anything that
+/// can't be encoded is reported and left as zeroes, so one pass finds every
+/// error. A `relocatable` program mustn't compute anything from addresses
+/// that the linker can't patch.
+fn emit(statements: Vec<Statement>, width: Width, imports: &[String], relocatable: bool) -> Result<Emitted, Vec<Diagnostic>> {
+    let mut labels = layout(&statements, width);
+    let (constants, mut errors) = constants(&statements, &labels, width);
+    for import in imports {
+        labels.entry(import.clone()).or_insert(0);
+    }
+
+    let mut out = Emitted::default();
+    for Statement { ir, at } in statements {
+        let offset = out.code.len();
+        let origins = RefCell::new(Vec::new());
+        let env = |position: usize| Env { map: &labels, constants: &constants, imports, width, position: position as Int, origins: &origins };
 
-    for ir in inter_rep {
         match ir {
-            InterRep::Instruction(i) => {
-                instrs.push(i)
+            InterRep::Instruction(instr) => {
+                let encoded = match instr(env(offset)) {
+                    Ok(instr) => encoding::encode(&instr, width).ok_or_else(|| {
+                        Diagnostic::error(at.clone(), format!("operand doesn't fit in a {}-byte word", width.bytes()))
+                    }),
+                    Err(e) => Err(convert_error(&at, e)),
+                };
+                match encoded {
+                    Ok(bytes) => out.code.extend(bytes),
+                    Err(e) => {
+                        errors.push(e);
+                        out.code.resize(offset + width.instr_size(), 0);
+                    },
+                }
+
+                let slots = origins.borrow().clone().into_iter().enumerate();
+                out.placed.extend(slots.map(|(n, origin)| (offset + encoding::slot_offset(n, width), origin)));
+            },
+            InterRep::Words(words) => {
+                out.code.resize(offset + words.len() * width.bytes(), 0);
+                for (n, word) in words.iter().enumerate() {
+                    errors.extend(out.word(word, env(offset + n * width.bytes()), &at).err());
+                }
+            },
+            InterRep::Cap(name, CapLiteral { start, end, perms }) => {
+                // a capability of either format fits a full-sized slot
+                let align = width.cap_size();
+                if offset % align != 0 {
+                    errors.push(Diagnostic::error(at.clone(), format!("`.cap` slot isn't aligned; put `.align {align}` before it")));
+                }
+                out.code.resize(offset + width.cap_size(), 0);
+                errors.extend(out.word(&start, env(offset), &at).err());
+                errors.extend(out.word(&end, env(offset + width.bytes()), &at).err());
+                out.grants.push(Grant { name, offset, perms });
             },
-            InterRep::Label(name) => {
-                labels.insert(name, (instrs.len() * size_of::<Instruction>()).try_into().unwrap());
+            InterRep::Bytes(bytes) => out.code.extend(bytes),
+            InterRep::Align(_) => out.code.resize(offset + size(&ir, offset, width), 0),
+            InterRep::Label(_) | InterRep::Export(_) | InterRep::Import(_) | InterRep::Equ(..) => (),
+        }
+
+        if relocatable && origins.borrow().contains(&Origin::Computed) {
+            errors.push(Diagnostic::error(at, "the linker can't adjust this expression when it moves the module"));
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(out),
+        false => Err(errors),
+    }
+}
+
+/// Assembles a whole program, to be placed at offset 0.
+pub fn compile(statements: Vec<Statement>, width: Width) -> Result<Image, Vec<Diagnostic>> {
+    let Emitted { code, grants, .. } = emit(statements, width, &[], false)?;
+    Ok(Image { width, grants, bytes: code })
+}
+
+fn lcm(a: usize, b: usize) -> usize {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    a / x * b
+}
+
+/// Compiles one module of a larger program into an object for the linker.
+pub fn assemble(statements: Vec<Statement>, width: Width) -> Result<Object, Vec<Diagnostic>> {
+    let labels = layout(&statements, width);
+
+    let mut align = width.bytes();
+    let mut imports = Vec::new();
+    let mut exports = Vec::new();
+    let mut errors = Vec::new();
+    for Statement { ir, at } in &statements {
+        match ir {
+            InterRep::Align(n) => align = lcm(align, (*n).max(1)),
+            InterRep::Cap(..) => align = lcm(align, width.cap_size()),
+            InterRep::Import(name) => imports.push(name.clone()),
+            InterRep::Export(name) => match labels.get(name) {
+                Some(offset) => exports.push((name.clone(), *offset)),
+                None => errors.push(undefined(at, name)),
             },
+            _ => (),
         }
     }
 
-    let res: Result<Vec<Instruction>, String> = instrs.into_iter().enumerate().map(|(n, instr)| {
-        instr(Env { map: &labels, position: (n * size_of::<Instruction>()) as Int }).clone()
+    let Emitted { code, placed, grants } = match emit(statements, width, &imports, true) {
+        Ok(emitted) if errors.is_empty() => emitted,
+        Ok(_) => return Err(errors),
+        Err(more) => {
+            errors.extend(more);
+            return Err(errors)
+        },
+    };
+    let relocs = placed.into_iter().filter_map(|(offset, origin)| {
+        let target = match origin {
+            Origin::Absolute => return None,
+            Origin::Computed => unreachable!("`emit` rejects these in a relocatable module"),
+            Origin::Here => Target::Base,
+            Origin::Label(name) if labels.contains_key(&name) => Target::Base,
+            Origin::Label(name) => Target::Import(name),
+        };
+        Some(Reloc { offset, target })
     }).collect();
 
-    res.map_err(|name| CompileError::UndefinedLabel(format!("Undefined label: {name}")))
-}
\ No newline at end of file
+    Ok(Object { width, align, code, exports, relocs, grants })
+}
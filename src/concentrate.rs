@@ -0,0 +1,202 @@
+//! CHERI Concentrate-style bounds compression.
+//!
+//! Instead of storing both bounds in full, a compressed capability keeps an
+//! exponent `E` and two `M`-bit mantissas, `B` and `T`: bits `E..E+M` of the
+//! base and top. The remaining high bits are taken from the pointer, so the
+//! bounds can only be recovered while the pointer stays inside a window of
+//! `2^(E+M)` bytes around them, the representable region.
+//!
+//! This costs precision twice over:
+//!
+//! - a bounds request is rounded outward to multiples of `2^E`, and `E` is
+//!   the smallest exponent that fits the length into half the mantissa
+//! - moving the pointer too far out of bounds makes the bounds decode to
+//!   something else, so the result loses its tag
+//!
+//! Real CHERI also borrows mantissa bits to encode the exponent; here `E` has
+//! a field of its own to keep the arithmetic readable.
+
+use std::ops::Range;
+
+use crate::bytecode::{Int, Width};
+
+/// The compressed bounds of one capability.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compressed {
+    pub exp: u32,
+    pub base: u64,
+    pub top: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Concentrate {
+    /// Bits in each of the `B` and `T` mantissas
+    mantissa: u32,
+    max_exp: u32,
+}
+
+impl Concentrate {
+    pub fn new(width: Width) -> Self {
+        let mantissa = match width {
+            Width::W16 | Width::W32 => 8,
+            Width::W64 => 14,
+        };
+        Self { mantissa, max_exp: width.bits() + 1 - mantissa }
+    }
+
+    /// Picks the smallest exponent whose granules fit `bounds` into half the
+    /// mantissa range, leaving the other half as representable slack.
+    pub fn encode(&self, bounds: Range<Int>) -> Compressed {
+        let (base, top) = (bounds.start as i128, bounds.end.max(bounds.start) as i128);
+        let limit = 1i128 << (self.mantissa - 1);
+
+        let exp = (0..self.max_exp)
+            .find(|&exp| Self::ceil(top, exp) - Self::floor(base, exp) <= limit)
+            .unwrap_or(self.max_exp);
+
+        let mask = (1i128 << self.mantissa) - 1;
+        Compressed {
+            exp,
+            base: (Self::floor(base, exp) & mask) as u64,
+            top: (Self::ceil(top, exp) & mask) as u64,
+        }
+    }
+
+    /// Recovers full bounds from the mantissas and the high bits of `ptr`.
+    pub fn decode(&self, c: Compressed, ptr: Int) -> Range<Int> {
+        let m = self.mantissa;
+        let (b, t) = (c.base as i128, c.top as i128);
+        let a = ptr as i128;
+
+        // the representable region starts a quarter of the mantissa range
+        // below the base; anything below that in the pointer's window
+        // belongs to the next window up
+        let r = (b - (1 << (m - 2))).rem_euclid(1 << m);
+        let a_mid = (a >> c.exp).rem_euclid(1 << m);
+        let upper = |x: i128| (x < r) as i128;
+
+        let window = a >> (c.exp + m);
+        let base = ((window + upper(b) - upper(a_mid)) << (c.exp + m)) + (b << c.exp);
+        let top = ((window + upper(t) - upper(a_mid)) << (c.exp + m)) + (t << c.exp);
+
+        Self::saturate(base)..Self::saturate(top)
+    }
+
+    /// The bounds a request for `bounds` actually gets.
+    pub fn round(&self, bounds: Range<Int>) -> Range<Int> {
+        let c = self.encode(bounds.clone());
+        self.decode(c, bounds.start)
+    }
+
+    /// The metadata word: perms and seal in the low 16 bits, then the
+    /// exponent and the mantissas.
+    pub fn pack(&self, c: Compressed, meta: u16) -> u64 {
+        meta as u64
+            | (c.exp as u64) << 16
+            | c.base << 22
+            | c.top << (22 + self.mantissa)
+    }
+
+    pub fn unpack(&self, word: u64) -> (Compressed, u16) {
+        let mask = (1 << self.mantissa) - 1;
+        let c = Compressed {
+            exp: (word >> 16 & 0x3f) as u32,
+            base: word >> 22 & mask,
+            top: word >> (22 + self.mantissa) & mask,
+        };
+        (c, word as u16)
+    }
+
+    fn floor(n: i128, exp: u32) -> i128 {
+        n >> exp
+    }
+
+    fn ceil(n: i128, exp: u32) -> i128 {
+        (n + (1 << exp) - 1) >> exp
+    }
+
+    fn saturate(n: i128) -> Int {
+        n.clamp(Int::MIN as i128, Int::MAX as i128) as Int
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{capability::{CapFormat, Capability, Inner, Permissions, Seal}, device::Rng};
+
+    /// Bounds of every scale, from a fixed random sequence.
+    fn requests(width: Width) -> Vec<Range<Int>> {
+        let mut rng = Rng::new(0x9e37_79b9_7f4a_7c15);
+        (0..2000).map(|_| {
+            let scale = rng.next() % (width.bits() - 1) as u64;
+            let len = (rng.next() & ((1 << scale) - 1)) as Int;
+            let start = width.read(&rng.next().to_le_bytes()) / 2;
+            start..start + len
+        }).collect()
+    }
+
+    fn cap(bounds: Range<Int>) -> Capability {
+        Capability {
+            inner: Inner::new(bounds.start, bounds, Permissions::rwx(true, true, false), Seal::Unsealed),
+            valid: true,
+        }
+    }
+
+    #[test]
+    fn rounding_covers_the_request() {
+        for width in Width::ALL {
+            let c = Concentrate::new(width);
+            for request in requests(width) {
+                let granted = c.round(request.clone());
+                assert!(granted.start <= request.start && request.end <= granted.end, "{request:?} got {granted:?} at {width:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn set_addr_keeps_the_tag_anywhere_in_bounds() {
+        for width in Width::ALL {
+            let format = CapFormat::compressed(width);
+            let c = Concentrate::new(width);
+            for request in requests(width) {
+                let bounds = c.round(request);
+                for addr in [bounds.start, bounds.start + (bounds.end - bounds.start) / 2, bounds.end.max(bounds.start + 1) - 1] {
+                    let moved = cap(bounds.clone()).set_addr(addr, format);
+                    assert!(moved.valid && moved.inner.bounds() == bounds, "{bounds:?} to {addr:#x} at {width:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn set_addr_drops_the_tag_outside_the_representable_region() {
+        for width in Width::ALL {
+            let format = CapFormat::compressed(width);
+            let c = Concentrate::new(width);
+            let m = c.mantissa;
+
+            // too long to fit half the mantissa range in 2-byte granules,
+            // short enough in 4-byte ones, so the exponent is 2
+            let base: Int = 1 << (m + 2);
+            let bounds = c.round(base..base + (3 << (m - 1)));
+            assert_eq!(c.encode(bounds.clone()).exp, 2);
+
+            // the region is 2^(E+M) bytes, starting a quarter of it below
+            // the base
+            let region = base - (1 << m)..base - (1 << m) + (1 << (m + 2));
+            for (addr, valid) in [
+                (region.start, true),
+                (region.start - 1, false),
+                (region.end - 1, true),
+                (region.end, false),
+            ] {
+                let moved = cap(bounds.clone()).set_addr(addr, format);
+                assert_eq!(moved.valid, valid, "{bounds:?} to {addr:#x} at {width:?}");
+                if valid {
+                    assert_eq!(moved.inner.bounds(), bounds);
+                }
+            }
+        }
+    }
+}
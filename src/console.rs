@@ -0,0 +1,99 @@
+//! Where guest I/O goes: `Emit` and `Input`, the UART and the framebuffer
+//! all talk to the machine's console rather than the host's stdio.
+
+use std::{cell::RefCell, collections::VecDeque, fs::File, io::{self, Read, Write}, rc::Rc};
+
+pub trait Console {
+    fn write(&mut self, byte: u8);
+
+    /// The next byte of input, or `None` once it's exhausted.
+    fn read(&mut self) -> Option<u8>;
+}
+
+/// The machine and its devices hold the same console, so swapping it on
+/// one swaps it for all of them.
+pub type Handle = Rc<RefCell<Box<dyn Console>>>;
+
+pub fn handle(console: impl Console + 'static) -> Handle {
+    Rc::new(RefCell::new(Box::new(console)))
+}
+
+fn read_byte(input: &mut impl Read) -> Option<u8> {
+    let mut byte = [0];
+    match input.read(&mut byte) {
+        Ok(1) => Some(byte[0]),
+        _ => None,
+    }
+}
+
+/// Writes to the host's stdout.
+pub struct StdoutConsole {
+    input: Box<dyn Read>,
+}
+
+impl StdoutConsole {
+    pub fn new(input: impl Read + 'static) -> Self {
+        Self { input: Box::new(input) }
+    }
+}
+
+impl Console for StdoutConsole {
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte]).and_then(|_| stdout.flush()).ok();
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        read_byte(&mut self.input)
+    }
+}
+
+/// Collects output in memory, and reads from a fixed input.
+pub struct BufferConsole {
+    output: Rc<RefCell<Vec<u8>>>,
+    input: VecDeque<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &[u8]) -> Self {
+        Self { output: Default::default(), input: input.iter().copied().collect() }
+    }
+
+    /// Everything written so far, still readable once the console has been
+    /// handed to a machine.
+    pub fn output(&self) -> Rc<RefCell<Vec<u8>>> {
+        self.output.clone()
+    }
+}
+
+impl Console for BufferConsole {
+    fn write(&mut self, byte: u8) {
+        self.output.borrow_mut().push(byte);
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.input.pop_front()
+    }
+}
+
+/// Writes to a file.
+pub struct FileConsole {
+    file: File,
+    input: Box<dyn Read>,
+}
+
+impl FileConsole {
+    pub fn new(file: File, input: impl Read + 'static) -> Self {
+        Self { file, input: Box::new(input) }
+    }
+}
+
+impl Console for FileConsole {
+    fn write(&mut self, byte: u8) {
+        self.file.write_all(&[byte]).ok();
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        read_byte(&mut self.input)
+    }
+}
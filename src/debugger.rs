@@ -0,0 +1,322 @@
+use std::{cell::RefCell, collections::{BTreeSet, HashMap}, fs, io::{self, BufRead, Write}, rc::Rc};
+
+use pom::parser::end;
+
+use crate::{bytecode::{Int, GpRegister, CRegister}, capability::Capability, parse, snapshot, vm::{Machine, RunOutcome}};
+
+const HELP: &str = "\
+commands:
+    s, step [n]               execute n instructions (default 1)
+    c, continue               run until a breakpoint, watchpoint, halt or fault
+    rs, rstep [n]             undo n instructions (default 1)
+    rc, rcontinue             undo until a breakpoint, watchpoint or the start
+                              of history; devices and output aren't rewound
+    b, break <label|addr>     stop before executing the instruction at addr
+    d, delete <label|addr>    remove a breakpoint
+    w, watch <reg>            stop when a register changes
+    w, watch <addr> [len]     stop when len bytes of memory (or their tags) change
+    u, unwatch                remove every watchpoint
+    r, regs                   print the register file
+    x, mem <addr> [len]       dump memory, with the tag of each granule
+    save <path>               write a snapshot of the machine to path
+    i, info                   list breakpoints, watchpoints, devices and how
+                              far back rstep can go
+    q, quit                   leave the debugger
+";
+
+#[derive(Clone, Copy)]
+enum Register {
+    Gp(GpRegister),
+    Cap(CRegister),
+    Pc,
+}
+
+#[derive(PartialEq)]
+enum Observed {
+    Int(Int),
+    Cap(Capability),
+    Memory(Vec<u8>, Vec<bool>),
+}
+
+enum Watch {
+    Register(Register),
+    Memory { addr: usize, len: usize },
+}
+
+impl Watch {
+    fn observe(&self, machine: &Machine) -> Observed {
+        match *self {
+            Watch::Register(Register::Gp(r)) => Observed::Int(machine.reg[r]),
+            Watch::Register(Register::Cap(c)) => Observed::Cap(machine.reg[c]),
+            Watch::Register(Register::Pc) => Observed::Int(machine.reg.pc()),
+            Watch::Memory { addr, len } => {
+                let cap_size = machine.memory.cap_size();
+                Observed::Memory(
+                    machine.memory.bytes(addr, len).to_vec(),
+                    (addr / cap_size..(addr + len).div_ceil(cap_size))
+                        .map(|granule| machine.memory.cap_tag(granule * cap_size))
+                        .collect(),
+                )
+            },
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Watch::Register(Register::Gp(r)) => r.to_string(),
+            Watch::Register(Register::Cap(c)) => c.to_string(),
+            Watch::Register(Register::Pc) => "pc".into(),
+            Watch::Memory { addr, len } => format!("{addr:04x}..{:04x}", addr + len),
+        }
+    }
+}
+
+enum Stop {
+    Breakpoint(usize),
+    Watch(usize),
+    Halted(Int),
+    Faulted(String),
+    /// Stepping back ran out of history
+    Start,
+    Done,
+}
+
+/// Ticks kept for stepping backwards.
+const HISTORY: usize = 100_000;
+
+pub struct Debugger {
+    machine: Machine,
+    labels: HashMap<String, Int>,
+    breakpoints: BTreeSet<usize>,
+    watches: Vec<(Watch, Observed)>,
+    /// The program's console output, shown as it arrives
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Debugger {
+    pub fn new(mut machine: Machine, labels: HashMap<String, Int>, output: Rc<RefCell<Vec<u8>>>) -> Self {
+        machine.record_history(HISTORY);
+        Self { machine, labels, breakpoints: BTreeSet::new(), watches: Vec::new(), output }
+    }
+
+    /// Address of the next instruction: `CC`'s pointer plus the PC offset.
+    fn addr(&self) -> usize {
+        (self.machine.reg[CRegister::CC].inner.ptr() + self.machine.reg.pc()) as usize
+    }
+
+    fn address(&self, arg: &str) -> Result<usize, String> {
+        if let Some(&offset) = self.labels.get(arg) {
+            return Ok(offset as usize)
+        }
+
+        let parsed = match arg.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => arg.parse(),
+        };
+        parsed.map_err(|_| format!("`{arg}` is neither a label nor an address"))
+    }
+
+    fn register(arg: &str) -> Option<Register> {
+        let bytes = arg.as_bytes();
+        if arg == "pc" {
+            Some(Register::Pc)
+        } else if let Ok(r) = (parse::gp_reg() - end()).parse(bytes) {
+            Some(Register::Gp(r))
+        } else {
+            (parse::c_reg() - end()).parse(bytes).ok().map(Register::Cap)
+        }
+    }
+
+    fn step(&mut self, first: bool) -> Option<Stop> {
+        if !first && self.breakpoints.contains(&self.addr()) {
+            return Some(Stop::Breakpoint(self.addr()))
+        }
+
+        match self.machine.run(Some(1)) {
+            RunOutcome::Halted(code) => return Some(Stop::Halted(code)),
+            RunOutcome::Faulted { error, pc } => return Some(Stop::Faulted(format!("fault at pc {pc:04x}: {error}"))),
+            RunOutcome::Exhausted => (),
+        }
+
+        self.changed_watch().map(Stop::Watch)
+    }
+
+    /// Steps backwards, stopping on arrival at a breakpoint rather than
+    /// before leaving one.
+    fn step_back(&mut self) -> Option<Stop> {
+        if !self.machine.step_back() {
+            return Some(Stop::Start)
+        }
+
+        if let Some(n) = self.changed_watch() {
+            return Some(Stop::Watch(n))
+        }
+        if self.breakpoints.contains(&self.addr()) {
+            return Some(Stop::Breakpoint(self.addr()))
+        }
+
+        None
+    }
+
+    fn changed_watch(&mut self) -> Option<usize> {
+        let machine = &self.machine;
+        for (n, (watch, last)) in self.watches.iter_mut().enumerate() {
+            let now = watch.observe(machine);
+            if now != *last {
+                *last = now;
+                return Some(n)
+            }
+        }
+
+        None
+    }
+
+    fn resume(&mut self, count: Option<usize>, backwards: bool) -> Stop {
+        let mut n = 0;
+        loop {
+            if count.is_some_and(|count| n >= count) {
+                return Stop::Done
+            }
+            let stop = match backwards {
+                true => self.step_back(),
+                false => self.step(n == 0),
+            };
+            if let Some(stop) = stop {
+                return stop
+            }
+            n += 1;
+        }
+    }
+
+    fn report(&self, stop: Stop) {
+        let output: Vec<u8> = self.output.borrow_mut().drain(..).collect();
+        if !output.is_empty() {
+            println!("output: {:?}", String::from_utf8_lossy(&output));
+        }
+
+        match stop {
+            Stop::Breakpoint(addr) => println!("breakpoint at {addr:04x}"),
+            Stop::Watch(n) => println!("watchpoint {} changed", self.watches[n].0.describe()),
+            Stop::Halted(code) => println!("halted with code {code}"),
+            Stop::Faulted(error) => println!("{error}"),
+            Stop::Start => println!("no more history"),
+            Stop::Done => (),
+        }
+        self.where_am_i();
+    }
+
+    fn where_am_i(&self) {
+        let label = self.labels.iter()
+            .find(|(_, &offset)| offset as usize == self.addr())
+            .map(|(name, _)| format!(" <{name}>"))
+            .unwrap_or_default();
+
+        match self.machine.next_instruction() {
+            Ok(instr) => println!("{:04x}{label}  {instr}", self.addr()),
+            Err(e) => println!("{:04x}{label}  ({e})", self.addr()),
+        }
+    }
+
+    fn dump(&self, addr: usize, len: usize) {
+        let cap_size = self.machine.memory.cap_size();
+        let start = addr - addr % cap_size;
+        for line in (start..addr + len).step_by(cap_size) {
+            let bytes = self.machine.memory.bytes(line, cap_size);
+            if bytes.is_empty() {
+                break
+            }
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{b:02x}")).collect();
+            let tag = if self.machine.memory.cap_tag(line) { '*' } else { ' ' };
+            println!("{line:04x}  [{tag}]  {}", hex.join(" "));
+        }
+    }
+
+    /// Runs one command, returning false once the user asks to quit.
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |n: usize| words.get(n).map(|w| w.parse::<usize>().map_err(|e| format!("{w}: {e}"))).transpose();
+
+        match words.as_slice() {
+            [] => (),
+            ["s" | "step", ..] => {
+                let stop = self.resume(Some(number(1)?.unwrap_or(1)), false);
+                self.report(stop);
+            },
+            ["c" | "continue"] => {
+                let stop = self.resume(None, false);
+                self.report(stop);
+            },
+            ["rs" | "rstep", ..] => {
+                let stop = self.resume(Some(number(1)?.unwrap_or(1)), true);
+                self.report(stop);
+            },
+            ["rc" | "rcontinue"] => {
+                let stop = self.resume(None, true);
+                self.report(stop);
+            },
+            ["b" | "break", at] => {
+                let addr = self.address(at)?;
+                self.breakpoints.insert(addr);
+                println!("breakpoint at {addr:04x}");
+            },
+            ["d" | "delete", at] => {
+                let addr = self.address(at)?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {addr:04x}"))
+                }
+            },
+            ["w" | "watch", reg] if Self::register(reg).is_some() => {
+                let watch = Watch::Register(Self::register(reg).unwrap());
+                let now = watch.observe(&self.machine);
+                self.watches.push((watch, now));
+            },
+            ["w" | "watch", at, ..] => {
+                let watch = Watch::Memory { addr: self.address(at)?, len: number(2)?.unwrap_or(1) };
+                let now = watch.observe(&self.machine);
+                self.watches.push((watch, now));
+            },
+            ["u" | "unwatch"] => self.watches.clear(),
+            ["r" | "regs"] => print!("{:#}", self.machine.reg),
+            ["x" | "mem", at, ..] => self.dump(self.address(at)?, number(2)?.unwrap_or(self.machine.memory.cap_size())),
+            ["save", path] => {
+                fs::write(path, snapshot::write(&self.machine)).map_err(|e| format!("{path}: {e}"))?;
+                println!("saved to {path}");
+            },
+            ["i" | "info"] => {
+                for addr in &self.breakpoints {
+                    println!("breakpoint {addr:04x}");
+                }
+                for (watch, _) in &self.watches {
+                    println!("watch {}", watch.describe());
+                }
+                for (range, device) in self.machine.memory.bus.iter() {
+                    println!("device {} {:04x}..{:04x}", device.name(), range.start, range.end);
+                }
+                println!("history {} ticks", self.machine.history_len());
+            },
+            ["h" | "help"] => print!("{HELP}"),
+            ["q" | "quit"] => return Ok(false),
+            _ => return Err(format!("unknown command `{line}`, try `help`")),
+        }
+
+        Ok(true)
+    }
+
+    pub fn repl(&mut self, input: impl BufRead) -> io::Result<()> {
+        self.where_am_i();
+        print!("(dbg) ");
+        io::stdout().flush()?;
+
+        for line in input.lines() {
+            match self.command(line?.trim()) {
+                Ok(true) => (),
+                Ok(false) => break,
+                Err(e) => println!("error: {e}"),
+            }
+            print!("(dbg) ");
+            io::stdout().flush()?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+//! Memory-mapped devices. Each device claims a window of the physical
+//! address space above RAM, and word-sized, aligned loads and stores that
+//! land in it are routed to the device instead of memory. They still go
+//! through a capability, so a program can only reach the devices it has been
+//! handed authority over.
+
+use std::ops::Range;
+
+use crate::{bytecode::{Int, Width}, console};
+
+pub trait Device {
+    fn name(&self) -> &str;
+
+    /// Bytes of address space the device claims.
+    fn size(&self, width: Width) -> usize;
+
+    fn load(&mut self, offset: usize, width: Width) -> Int;
+
+    fn store(&mut self, offset: usize, value: Int, width: Width);
+
+    /// Called once per machine tick.
+    fn tick(&mut self) {}
+
+    /// Takes the device's pending interrupt, if it has one.
+    fn interrupt(&mut self) -> bool {
+        false
+    }
+
+    /// Internal state for a snapshot, empty if the device keeps none.
+    fn save(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Puts back state from `save`, returning false if it doesn't fit.
+    fn restore(&mut self, state: &[u8]) -> bool {
+        state.is_empty()
+    }
+}
+
+/// Routes physical addresses to the devices mapped there.
+#[derive(Default)]
+pub struct Bus {
+    devices: Vec<(Range<usize>, Box<dyn Device>)>,
+}
+
+impl Bus {
+    /// Maps `device` at `base`, which the caller keeps clear of RAM and of
+    /// every other device.
+    pub fn map(&mut self, base: usize, device: Box<dyn Device>, width: Width) {
+        let range = base..base + device.size(width);
+        self.devices.push((range, device));
+    }
+
+    pub fn end(&self) -> Option<usize> {
+        self.devices.iter().map(|(range, _)| range.end).max()
+    }
+
+    /// The device wholly containing `range`, and the offset of its start.
+    pub fn find(&mut self, range: &Range<usize>) -> Option<(&mut (dyn Device + 'static), usize)> {
+        self.devices.iter_mut()
+            .find(|(window, _)| window.start <= range.start && range.end <= window.end)
+            .map(|(window, device)| (device.as_mut(), range.start - window.start))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Range<usize>, &dyn Device)> {
+        self.devices.iter().map(|(range, device)| (range, device.as_ref()))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut (dyn Device + 'static)> {
+        self.devices.iter_mut().map(|(_, device)| device.as_mut())
+    }
+
+    pub fn tick(&mut self) {
+        for (_, device) in &mut self.devices {
+            device.tick();
+        }
+    }
+
+    /// The lowest-numbered device with an interrupt pending, which is
+    /// then cleared. A device's number is its position on the bus.
+    pub fn interrupt(&mut self) -> Option<usize> {
+        self.devices.iter_mut().position(|(_, device)| device.interrupt())
+    }
+}
+
+/// The devices every machine starts with.
+pub fn standard(console: console::Handle) -> Vec<Box<dyn Device>> {
+    vec![
+        Box::new(Uart { console: console.clone() }),
+        Box::new(Timer::default()),
+        Box::new(Rng::new(0x2545_f491_4f6c_dd1d)),
+        Box::new(Framebuffer::new(console, 32, 16)),
+    ]
+}
+
+/// A serial port on the machine's console. Storing writes a byte; loading
+/// reads one, or -1 once input runs out.
+pub struct Uart {
+    console: console::Handle,
+}
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn size(&self, width: Width) -> usize {
+        width.bytes()
+    }
+
+    fn load(&mut self, _offset: usize, _width: Width) -> Int {
+        self.console.borrow_mut().read().map_or(-1, Int::from)
+    }
+
+    fn store(&mut self, _offset: usize, value: Int, _width: Width) {
+        self.console.borrow_mut().write(value as u8);
+    }
+}
+
+/// Counts machine ticks in word 0, which a store overwrites. Storing n to
+/// word 1 raises an interrupt every n ticks from then on; 0 stops it.
+#[derive(Default)]
+pub struct Timer {
+    count: Int,
+    interval: Int,
+    remaining: Int,
+    pending: bool,
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn size(&self, width: Width) -> usize {
+        2 * width.bytes()
+    }
+
+    fn load(&mut self, offset: usize, width: Width) -> Int {
+        match offset {
+            0 => width.read(&self.count.to_le_bytes()),
+            _ => self.interval,
+        }
+    }
+
+    fn store(&mut self, offset: usize, value: Int, _width: Width) {
+        match offset {
+            0 => self.count = value,
+            _ => {
+                self.interval = value.max(0);
+                self.remaining = self.interval;
+                self.pending = false;
+            },
+        }
+    }
+
+    fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+
+        if self.interval > 0 {
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.pending = true;
+                self.remaining = self.interval;
+            }
+        }
+    }
+
+    fn interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.pending)
+    }
+
+    fn save(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for field in [self.count, self.interval, self.remaining] {
+            out.extend(field.to_le_bytes());
+        }
+        out.push(self.pending as u8);
+        out
+    }
+
+    fn restore(&mut self, state: &[u8]) -> bool {
+        if state.len() != 3 * 8 + 1 {
+            return false
+        }
+
+        let field = |n: usize| Int::from_le_bytes(state[n * 8..n * 8 + 8].try_into().unwrap());
+        (self.count, self.interval, self.remaining) = (field(0), field(1), field(2));
+        self.pending = state[24] != 0;
+        true
+    }
+}
+
+/// A xorshift generator. Every load yields a fresh word; a store reseeds it.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    /// Steps the xorshift generator.
+    pub fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl Device for Rng {
+    fn name(&self) -> &str {
+        "rng"
+    }
+
+    fn size(&self, width: Width) -> usize {
+        width.bytes()
+    }
+
+    fn load(&mut self, _offset: usize, width: Width) -> Int {
+        width.read(&self.next().to_le_bytes())
+    }
+
+    fn store(&mut self, _offset: usize, value: Int, _width: Width) {
+        *self = Self::new(value as u64);
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.state.to_le_bytes().to_vec()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> bool {
+        match state.try_into() {
+            Ok(state) => {
+                self.state = u64::from_le_bytes(state);
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
+/// A grid of one-byte pixels, followed by a control word. Storing to the
+/// control word draws the grid to the machine's console.
+pub struct Framebuffer {
+    console: console::Handle,
+    columns: usize,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    const SHADES: &[u8] = b" .:-=+*#%@";
+
+    pub fn new(console: console::Handle, columns: usize, rows: usize) -> Self {
+        Self { console, columns, pixels: vec![0; columns * rows] }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for row in self.pixels.chunks(self.columns) {
+            for &pixel in row {
+                let shade = pixel as usize * (Self::SHADES.len() - 1) / u8::MAX as usize;
+                out.push(Self::SHADES[shade] as char);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &str {
+        "framebuffer"
+    }
+
+    fn size(&self, width: Width) -> usize {
+        self.pixels.len().next_multiple_of(width.bytes()) + width.bytes()
+    }
+
+    fn load(&mut self, offset: usize, width: Width) -> Int {
+        match self.pixels.get(offset..offset + width.bytes()) {
+            Some(bytes) => width.read(bytes),
+            None => 0,
+        }
+    }
+
+    fn store(&mut self, offset: usize, value: Int, width: Width) {
+        if offset >= self.pixels.len() {
+            let mut console = self.console.borrow_mut();
+            for byte in self.render().bytes() {
+                console.write(byte);
+            }
+            return
+        }
+
+        let mut bytes = vec![0; width.bytes()];
+        width.write(value, &mut bytes);
+        for (pixel, byte) in self.pixels[offset..].iter_mut().zip(bytes) {
+            *pixel = byte;
+        }
+    }
+
+    fn save(&self) -> Vec<u8> {
+        self.pixels.clone()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> bool {
+        if state.len() != self.pixels.len() {
+            return false
+        }
+
+        self.pixels.copy_from_slice(state);
+        true
+    }
+}
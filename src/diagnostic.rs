@@ -0,0 +1,96 @@
+//! Errors and warnings about assembly source, rendered with the line they
+//! point at:
+//!
+//! ```text
+//! error: undefined label `lop`
+//!   --> main.s:4:12
+//!    |
+//!  4 |     jmp cc #lop
+//!    |            ^
+//! ```
+
+use std::{fmt::Display, rc::Rc};
+
+/// One line of source, after includes and macros have been expanded.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub file: Rc<str>,
+    /// Counting from 1. Lines a macro expands to share its invocation's.
+    pub number: usize,
+    pub text: Rc<str>,
+    /// The macro this line came out of, if any
+    pub expansion: Option<Rc<str>>,
+}
+
+impl Line {
+    /// Points at `column`, counting from 1.
+    pub fn at(&self, column: usize) -> Location {
+        Location { line: self.clone(), column }
+    }
+
+    /// Points at the first thing on the line.
+    pub fn start(&self) -> Location {
+        self.at(self.text.len() - self.text.trim_start().len() + 1)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub line: Line,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub location: Location,
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(location: Location, message: impl Into<String>) -> Self {
+        Self { severity: Severity::Error, message: message.into(), location, note: None }
+    }
+
+    pub fn warning(location: Location, message: impl Into<String>) -> Self {
+        Self { severity: Severity::Warning, message: message.into(), location, note: None }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.note = Some(note.into());
+        self
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Location { line, column } = &self.location;
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let gutter = " ".repeat(line.number.to_string().len());
+
+        writeln!(f, "{severity}: {}", self.message)?;
+        writeln!(f, "{gutter}--> {}:{}:{column}", line.file, line.number)?;
+        writeln!(f, "{gutter} |")?;
+        writeln!(f, "{} | {}", line.number, line.text)?;
+        // keep tabs so the caret lines up under them
+        let pad: String = line.text.bytes().take(column - 1).map(|b| if b == b'\t' { '\t' } else { ' ' }).collect();
+        writeln!(f, "{gutter} | {pad}^")?;
+        if let Some(name) = &line.expansion {
+            writeln!(f, "{gutter} = note: in an expansion of macro `{name}`")?;
+        }
+        if let Some(note) = &self.note {
+            writeln!(f, "{gutter} = note: {note}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::{collections::BTreeMap, fmt::{Display, Write}};
+
+use crate::{bytecode::{Instruction, Value, Int, Width, GpRegister, CRegister, Condition, SpecialRegister}, encoding, image::Grant};
+
+// Everything here prints in the syntax `parse::parse` accepts, so the
+// output of `disassemble` can be fed straight back to the assembler.
+
+impl Display for GpRegister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpRegister::SP => write!(f, "sp"),
+            r => write!(f, "r{}", *r as u8),
+        }
+    }
+}
+
+impl Display for CRegister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CRegister::CC => write!(f, "cc"),
+            CRegister::DD => write!(f, "dd"),
+            c => write!(f, "c{}", *c as u8),
+        }
+    }
+}
+
+impl Display for SpecialRegister {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SpecialRegister::Tcc => "tcc",
+            SpecialRegister::Epcc => "epcc",
+            SpecialRegister::Ecap => "ecap",
+            SpecialRegister::Ivec => "ivec",
+            SpecialRegister::Idd => "idd",
+            SpecialRegister::Ipcc => "ipcc",
+            SpecialRegister::Ipdd => "ipdd",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            Condition::L => "<",
+            Condition::LE => "<=",
+            Condition::E => "==",
+            Condition::GE => ">=",
+            Condition::G => ">",
+        };
+        write!(f, "{op}")
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Reg(r) => write!(f, "{r}"),
+            Value::Imm(n) => write!(f, "{n}"),
+        }
+    }
+}
+
+impl Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use Instruction::*;
+        match self {
+            Mov(a, b) => write!(f, "mov {a} {b}"),
+
+            Add(a, b) => write!(f, "add {a} {b}"),
+            Sub(a, b) => write!(f, "sub {a} {b}"),
+            Mul(a, b) => write!(f, "mul {a} {b}"),
+            Div(a, b) => write!(f, "div {a} {b}"),
+
+            And(a, b) => write!(f, "and {a} {b}"),
+            Or(a, b) => write!(f, "or {a} {b}"),
+            Xor(a, b) => write!(f, "xor {a} {b}"),
+            Not(a) => write!(f, "not {a}"),
+
+            Load(a, c, b) => write!(f, "load {a} {c} {b}"),
+            Store(c, a, b) => write!(f, "store {c} {a} {b}"),
+            Jmp(c, a) => write!(f, "jmp {c} {a}"),
+
+            Push(a) => write!(f, "push {a}"),
+            Pop(a) => write!(f, "pop {a}"),
+
+            Cond(a, c, b) => write!(f, "cond {a} {c} {b}"),
+
+            Emit(a) => write!(f, "emit {a}"),
+            Input(a) => write!(f, "input {a}"),
+            Halt(a) => write!(f, "halt {a}"),
+
+            CLoad(a, c) => write!(f, "cload {a} {c}"),
+            CStore(a, c) => write!(f, "cstore {a} {c}"),
+
+            CLoadCap(c, d, a) => write!(f, "cloadcap {c} {d} {a}"),
+            CStoreCap(c, a, d) => write!(f, "cstorecap {c} {a} {d}"),
+            CJmp(c) => write!(f, "cjmp {c}"),
+
+            CPushCap(c) => write!(f, "cpushcap {c}"),
+            CPopCap(c) => write!(f, "cpopcap {c}"),
+
+            CInvoke(c, d) => write!(f, "cinvoke {c} {d}"),
+            CCall(c, d) => write!(f, "ccall {c} {d}"),
+            CReturn => write!(f, "creturn"),
+            CSeal(c, d) => write!(f, "cseal {c} {d}"),
+            CUnseal(c, d) => write!(f, "cunseal {c} {d}"),
+
+            CSetBounds(c, d, a) => write!(f, "csetbounds {c} {d} {a}"),
+            CAndPerm(c, d, a) => write!(f, "candperm {c} {d} {a}"),
+            CIncOffset(c, d, a) => write!(f, "cincoffset {c} {d} {a}"),
+
+            CReadSpecial(c, s) => write!(f, "cspecialr {c} {s}"),
+            CWriteSpecial(s, c) => write!(f, "cspecialw {s} {c}"),
+            Cause(a) => write!(f, "cause {a}"),
+            TRet => write!(f, "tret"),
+
+            Ei => write!(f, "ei"),
+            Di => write!(f, "di"),
+            IRet => write!(f, "iret"),
+            IpcRead(a) => write!(f, "ipcr {a}"),
+            IpcWrite(a) => write!(f, "ipcw {a}"),
+        }
+    }
+}
+
+/// A run of encoded memory: an instruction, a `.cap` slot, or bytes that
+/// aren't either.
+enum Chunk {
+    Instr(Instruction),
+    Cap { grant: Grant, start: Int, end: Int },
+    Bytes(Vec<u8>),
+}
+
+/// Renders `chunks` as assembly, the first placed at offset 0. Immediate
+/// targets of `jmp cc` that land on an instruction get a synthesized label.
+fn disassemble(chunks: &[(Int, Chunk)]) -> String {
+    let labels: BTreeMap<Int, String> = chunks.iter().filter_map(|(_, chunk)| match chunk {
+        Chunk::Instr(Instruction::Jmp(CRegister::CC, Value::Imm(target)))
+            if chunks.iter().any(|(at, chunk)| at == target && matches!(chunk, Chunk::Instr(_))) =>
+                Some((*target, format!("l{target:04x}"))),
+        _ => None,
+    }).collect();
+
+    let mut out = String::new();
+    for (at, chunk) in chunks {
+        if let Some(label) = labels.get(at) {
+            writeln!(out, "{label}:").unwrap();
+        }
+
+        match chunk {
+            Chunk::Instr(Instruction::Jmp(CRegister::CC, Value::Imm(target))) if labels.contains_key(target) =>
+                writeln!(out, "    jmp cc #{}", labels[target]),
+            Chunk::Instr(instr) => writeln!(out, "    {instr}"),
+            Chunk::Cap { grant, start, end } => writeln!(out, "    .cap {}, {start}, {end}, {}", grant.name, grant.perms),
+            Chunk::Bytes(bytes) => {
+                let bytes: Vec<_> = bytes.iter().map(|b| format!("{b:#04x}")).collect();
+                writeln!(out, "    .byte {}", bytes.join(", "))
+            },
+        }.unwrap();
+    }
+
+    out
+}
+
+/// Disassembles a range of encoded memory, with the `.cap` slots in
+/// `grants` at offsets into it. Whatever doesn't decode to an instruction
+/// comes out as `.byte` lines, so the result assembles to exactly `bytes`.
+pub fn disassemble_bytes(bytes: &[u8], grants: &[Grant], width: Width) -> String {
+    const BYTES_PER_LINE: usize = 8;
+
+    let mut chunks: Vec<(Int, Chunk)> = Vec::new();
+    let mut at = 0;
+    while at < bytes.len() {
+        let rest = &bytes[at..];
+
+        // a slot is only a `.cap` if it holds nothing but the region, as
+        // the assembler leaves it
+        let slot = rest.get(..width.cap_size()).filter(|slot| slot[2 * width.bytes()..].iter().all(|&b| b == 0));
+        if let (Some(grant), Some(slot)) = (grants.iter().find(|grant| grant.offset == at), slot) {
+            let (start, end) = (width.read(slot), width.read(&slot[width.bytes()..]));
+            chunks.push((at as Int, Chunk::Cap { grant: grant.clone(), start, end }));
+            at += width.cap_size();
+            continue
+        }
+
+        let instr = encoding::decode(rest, width)
+            .filter(|_| !grants.iter().any(|grant| (at..at + width.instr_size()).contains(&grant.offset)));
+        match (instr, chunks.last_mut()) {
+            (Some(instr), _) => {
+                chunks.push((at as Int, Chunk::Instr(instr)));
+                at += width.instr_size();
+                continue
+            },
+            (None, Some((_, Chunk::Bytes(run)))) if run.len() < BYTES_PER_LINE => run.push(rest[0]),
+            (None, _) => chunks.push((at as Int, Chunk::Bytes(vec![rest[0]]))),
+        }
+        at += 1;
+    }
+
+    disassemble(&chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{compile, image::Image, parse, preprocess};
+
+    fn assemble(source: &str, width: Width) -> Image {
+        let lines = preprocess::expand(source, Path::new("test.s")).unwrap();
+        compile::compile(parse::parse(&lines).unwrap(), width).unwrap()
+    }
+
+    #[test]
+    fn reassembles_to_the_same_image() {
+        let source = "
+            mov r0 #msg
+            jmp cc #done
+        msg:
+            .asciz \"hello\"
+        done:
+            halt 0
+            .word 0x1234, -1
+            .align 16
+            .cap table, #table_start, #table_end, r--
+        table_start:
+            .byte 1, 2, 3
+        table_end:
+        ";
+
+        for width in Width::ALL {
+            let image = assemble(source, width);
+            let text = disassemble_bytes(&image.bytes, &image.grants, width);
+            assert!(text.contains(".cap table"), "{text}");
+            assert_eq!(assemble(&text, width).write(), image.write(), "{text}");
+        }
+    }
+}
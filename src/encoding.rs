@@ -0,0 +1,353 @@
+//! The fixed binary encoding of `Instruction`, independent of rustc's enum
+//! layout. Every instruction is `Width::instr_size` bytes:
+//!
+//! ```text
+//! [opcode] [reg a] [reg b] [imm flags] [slot 0: word LE] [slot 1: word LE]
+//! ```
+//!
+//! `reg a`/`reg b` hold register or condition indices. Each `Value` operand
+//! takes a slot in the order it appears; bit n of the flags is set when
+//! slot n is an immediate rather than a register index. Unused fields are
+//! zero, and opcode 0 is never assigned, so zeroed memory doesn't execute.
+
+use crate::bytecode::{Instruction, Int, Value, GpRegister, CRegister, Condition, SpecialRegister, Width};
+
+mod op {
+    pub const MOV: u8 = 0x01;
+    pub const ADD: u8 = 0x02;
+    pub const SUB: u8 = 0x03;
+    pub const MUL: u8 = 0x04;
+    pub const DIV: u8 = 0x05;
+    pub const AND: u8 = 0x06;
+    pub const OR: u8 = 0x07;
+    pub const XOR: u8 = 0x08;
+    pub const NOT: u8 = 0x09;
+    pub const LOAD: u8 = 0x0a;
+    pub const STORE: u8 = 0x0b;
+    pub const JMP: u8 = 0x0c;
+    pub const PUSH: u8 = 0x0d;
+    pub const POP: u8 = 0x0e;
+    pub const COND: u8 = 0x0f;
+    pub const EMIT: u8 = 0x10;
+    pub const HALT: u8 = 0x11;
+    pub const INPUT: u8 = 0x12;
+
+    pub const CLOAD: u8 = 0x20;
+    pub const CSTORE: u8 = 0x21;
+    pub const CLOADCAP: u8 = 0x22;
+    pub const CSTORECAP: u8 = 0x23;
+    pub const CJMP: u8 = 0x24;
+    pub const CPUSHCAP: u8 = 0x25;
+    pub const CPOPCAP: u8 = 0x26;
+    pub const CINVOKE: u8 = 0x27;
+    pub const CCALL: u8 = 0x28;
+    pub const CRETURN: u8 = 0x29;
+    pub const CSEAL: u8 = 0x2a;
+    pub const CUNSEAL: u8 = 0x2b;
+    pub const CSETBOUNDS: u8 = 0x2c;
+    pub const CANDPERM: u8 = 0x2d;
+    pub const CINCOFFSET: u8 = 0x2e;
+
+    pub const CREADSPECIAL: u8 = 0x30;
+    pub const CWRITESPECIAL: u8 = 0x31;
+    pub const CAUSE: u8 = 0x32;
+    pub const TRET: u8 = 0x33;
+    pub const EI: u8 = 0x34;
+    pub const DI: u8 = 0x35;
+    pub const IRET: u8 = 0x36;
+    pub const IPCREAD: u8 = 0x37;
+    pub const IPCWRITE: u8 = 0x38;
+}
+
+struct Fields {
+    op: u8,
+    a: u8,
+    b: u8,
+    values: [Option<Value>; 2],
+}
+
+impl Fields {
+    fn new(op: u8, a: u8, b: u8, values: [Option<Value>; 2]) -> Self {
+        Self { op, a, b, values }
+    }
+}
+
+fn fields(instr: &Instruction) -> Fields {
+    use Instruction::*;
+    use op::*;
+    let f = Fields::new;
+
+    match *instr {
+        Mov(a, v) => f(MOV, a as u8, 0, [Some(v), None]),
+
+        Add(a, v) => f(ADD, a as u8, 0, [Some(v), None]),
+        Sub(a, v) => f(SUB, a as u8, 0, [Some(v), None]),
+        Mul(a, v) => f(MUL, a as u8, 0, [Some(v), None]),
+        Div(a, v) => f(DIV, a as u8, 0, [Some(v), None]),
+
+        And(a, v) => f(AND, a as u8, 0, [Some(v), None]),
+        Or(a, v) => f(OR, a as u8, 0, [Some(v), None]),
+        Xor(a, v) => f(XOR, a as u8, 0, [Some(v), None]),
+        Not(a) => f(NOT, a as u8, 0, [None, None]),
+
+        Load(a, c, v) => f(LOAD, a as u8, c as u8, [Some(v), None]),
+        Store(c, v, w) => f(STORE, c as u8, 0, [Some(v), Some(w)]),
+        Jmp(c, v) => f(JMP, c as u8, 0, [Some(v), None]),
+
+        Push(v) => f(PUSH, 0, 0, [Some(v), None]),
+        Pop(a) => f(POP, a as u8, 0, [None, None]),
+
+        Cond(a, c, v) => f(COND, a as u8, c as u8, [Some(v), None]),
+
+        Emit(v) => f(EMIT, 0, 0, [Some(v), None]),
+        Input(a) => f(INPUT, a as u8, 0, [None, None]),
+        Halt(v) => f(HALT, 0, 0, [Some(v), None]),
+
+        CLoad(a, c) => f(CLOAD, a as u8, c as u8, [None, None]),
+        CStore(v, c) => f(CSTORE, c as u8, 0, [Some(v), None]),
+
+        CLoadCap(c, d, v) => f(CLOADCAP, c as u8, d as u8, [Some(v), None]),
+        CStoreCap(c, v, d) => f(CSTORECAP, c as u8, d as u8, [Some(v), None]),
+        CJmp(c) => f(CJMP, c as u8, 0, [None, None]),
+
+        CPushCap(c) => f(CPUSHCAP, c as u8, 0, [None, None]),
+        CPopCap(c) => f(CPOPCAP, c as u8, 0, [None, None]),
+
+        CInvoke(c, d) => f(CINVOKE, c as u8, d as u8, [None, None]),
+        CCall(c, d) => f(CCALL, c as u8, d as u8, [None, None]),
+        CReturn => f(CRETURN, 0, 0, [None, None]),
+        CSeal(c, d) => f(CSEAL, c as u8, d as u8, [None, None]),
+        CUnseal(c, d) => f(CUNSEAL, c as u8, d as u8, [None, None]),
+
+        CSetBounds(c, d, v) => f(CSETBOUNDS, c as u8, d as u8, [Some(v), None]),
+        CAndPerm(c, d, v) => f(CANDPERM, c as u8, d as u8, [Some(v), None]),
+        CIncOffset(c, d, v) => f(CINCOFFSET, c as u8, d as u8, [Some(v), None]),
+
+        CReadSpecial(c, s) => f(CREADSPECIAL, c as u8, s as u8, [None, None]),
+        CWriteSpecial(s, c) => f(CWRITESPECIAL, s as u8, c as u8, [None, None]),
+        Cause(a) => f(CAUSE, a as u8, 0, [None, None]),
+        TRet => f(TRET, 0, 0, [None, None]),
+
+        Ei => f(EI, 0, 0, [None, None]),
+        Di => f(DI, 0, 0, [None, None]),
+        IRet => f(IRET, 0, 0, [None, None]),
+        IpcRead(a) => f(IPCREAD, a as u8, 0, [None, None]),
+        IpcWrite(v) => f(IPCWRITE, 0, 0, [Some(v), None]),
+    }
+}
+
+/// Where immediate slot `n` starts within an encoded instruction.
+pub fn slot_offset(n: usize, width: Width) -> usize {
+    4 + n * width.bytes()
+}
+
+/// Encodes `instr`, or `None` if an immediate doesn't fit in a word.
+pub fn encode(instr: &Instruction, width: Width) -> Option<Vec<u8>> {
+    let Fields { op, a, b, values } = fields(instr);
+    let mut out = vec![0; width.instr_size()];
+    out[0] = op;
+    out[1] = a;
+    out[2] = b;
+
+    for (n, value) in values.into_iter().enumerate() {
+        let slot = match value {
+            Some(Value::Imm(imm)) if width.fits(imm) => {
+                out[3] |= 1 << n;
+                imm
+            },
+            Some(Value::Imm(_)) => return None,
+            Some(Value::Reg(r)) => r as Int,
+            None => 0,
+        };
+        width.write(slot, &mut out[slot_offset(n, width)..]);
+    }
+
+    Some(out)
+}
+
+/// Decodes the instruction at the start of `bytes`, or `None` if it isn't
+/// one `encode` could have produced.
+pub fn decode(bytes: &[u8], width: Width) -> Option<Instruction> {
+    use Instruction::*;
+    use op::*;
+
+    let bytes = bytes.get(..width.instr_size())?;
+    let [op, a, b, flags, ..] = *bytes else { return None };
+
+    let slot = |n: usize| width.read(&bytes[slot_offset(n, width)..]);
+    let value = |n: usize| {
+        if flags & (1 << n) != 0 {
+            Some(Value::Imm(slot(n)))
+        } else {
+            u8::try_from(slot(n)).ok().and_then(|r| GpRegister::try_from(r).ok()).map(Value::Reg)
+        }
+    };
+    let gp = |r: u8| GpRegister::try_from(r).ok();
+    let cr = |r: u8| CRegister::try_from(r).ok();
+    let cond = |r: u8| Condition::try_from(r).ok();
+    let special = |r: u8| SpecialRegister::try_from(r).ok();
+
+    let instr = match op {
+        MOV => Mov(gp(a)?, value(0)?),
+
+        ADD => Add(gp(a)?, value(0)?),
+        SUB => Sub(gp(a)?, value(0)?),
+        MUL => Mul(gp(a)?, value(0)?),
+        DIV => Div(gp(a)?, value(0)?),
+
+        AND => And(gp(a)?, value(0)?),
+        OR => Or(gp(a)?, value(0)?),
+        XOR => Xor(gp(a)?, value(0)?),
+        NOT => Not(gp(a)?),
+
+        LOAD => Load(gp(a)?, cr(b)?, value(0)?),
+        STORE => Store(cr(a)?, value(0)?, value(1)?),
+        JMP => Jmp(cr(a)?, value(0)?),
+
+        PUSH => Push(value(0)?),
+        POP => Pop(gp(a)?),
+
+        COND => Cond(gp(a)?, cond(b)?, value(0)?),
+
+        EMIT => Emit(value(0)?),
+        INPUT => Input(gp(a)?),
+        HALT => Halt(value(0)?),
+
+        CLOAD => CLoad(gp(a)?, cr(b)?),
+        CSTORE => CStore(value(0)?, cr(a)?),
+
+        CLOADCAP => CLoadCap(cr(a)?, cr(b)?, value(0)?),
+        CSTORECAP => CStoreCap(cr(a)?, value(0)?, cr(b)?),
+        CJMP => CJmp(cr(a)?),
+
+        CPUSHCAP => CPushCap(cr(a)?),
+        CPOPCAP => CPopCap(cr(a)?),
+
+        CINVOKE => CInvoke(cr(a)?, cr(b)?),
+        CCALL => CCall(cr(a)?, cr(b)?),
+        CRETURN => CReturn,
+        CSEAL => CSeal(cr(a)?, cr(b)?),
+        CUNSEAL => CUnseal(cr(a)?, cr(b)?),
+
+        CSETBOUNDS => CSetBounds(cr(a)?, cr(b)?, value(0)?),
+        CANDPERM => CAndPerm(cr(a)?, cr(b)?, value(0)?),
+        CINCOFFSET => CIncOffset(cr(a)?, cr(b)?, value(0)?),
+
+        CREADSPECIAL => CReadSpecial(cr(a)?, special(b)?),
+        CWRITESPECIAL => CWriteSpecial(special(a)?, cr(b)?),
+        CAUSE => Cause(gp(a)?),
+        TRET => TRet,
+
+        EI => Ei,
+        DI => Di,
+        IRET => IRet,
+        IPCREAD => IpcRead(gp(a)?),
+        IPCWRITE => IpcWrite(value(0)?),
+
+        _ => return None,
+    };
+
+    // stray bits in fields the opcode doesn't use make the encoding illegal
+    (encode(&instr, width).as_deref() == Some(bytes)).then_some(instr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One of every instruction, with operands chosen so no field is left
+    /// at its default.
+    fn every_instruction(width: Width) -> Vec<Instruction> {
+        use Instruction::*;
+        use GpRegister::*;
+        use CRegister::*;
+
+        let imm = Value::Imm(width.min());
+        let reg = Value::Reg(R6);
+        vec![
+            Mov(R1, imm), Add(R2, reg), Sub(R3, Value::Imm(width.max())), Mul(R4, imm), Div(R5, reg),
+            And(R6, imm), Or(SP, reg), Xor(R1, imm), Not(R2),
+            Load(R3, C4, imm), Store(C5, reg, Value::Imm(-1)), Jmp(CC, imm),
+            Push(reg), Pop(R4),
+            Cond(R5, Condition::GE, imm),
+            Emit(reg), Input(R6), Halt(imm),
+            CReadSpecial(C1, SpecialRegister::Ipdd), CWriteSpecial(SpecialRegister::Ecap, C2), Cause(R1), TRet,
+            Ei, Di, IRet, IpcRead(R2), IpcWrite(imm),
+            CLoad(R3, DD), CStore(reg, C3),
+            CLoadCap(C1, C2, imm), CStoreCap(C3, reg, C4), CJmp(C5),
+            CPushCap(CC), CPopCap(DD),
+            CInvoke(C1, C2), CCall(C3, C4), CReturn, CSeal(C5, C0), CUnseal(C0, C5),
+            CSetBounds(C1, C2, imm), CAndPerm(C3, C4, reg), CIncOffset(C5, CC, Value::Imm(1)),
+        ]
+    }
+
+    #[test]
+    fn round_trips_every_instruction() {
+        for width in Width::ALL {
+            let instrs = every_instruction(width);
+            let mut opcodes: Vec<_> = instrs.iter().map(|instr| fields(instr).op).collect();
+            opcodes.sort();
+            opcodes.dedup();
+            assert_eq!(opcodes.len(), instrs.len(), "each opcode once");
+            for op in 0..=u8::MAX {
+                let decodes = (0..64).any(|fields: u8| {
+                    let mut bytes = vec![0; width.instr_size()];
+                    bytes[..4].copy_from_slice(&[op, fields & 7, fields >> 3 & 7, 0]);
+                    (0..4).any(|flags| {
+                        bytes[3] = flags;
+                        decode(&bytes, width).is_some()
+                    })
+                });
+                assert_eq!(decodes, opcodes.contains(&op), "opcode {op:#04x} is missing");
+            }
+
+            for instr in instrs {
+                let bytes = encode(&instr, width).unwrap();
+                assert_eq!(bytes.len(), width.instr_size());
+                let decoded = decode(&bytes, width).unwrap_or_else(|| panic!("{instr:?} at {width:?}"));
+                assert_eq!(format!("{decoded:?}"), format!("{instr:?}"));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_immediates_wider_than_a_word() {
+        assert!(encode(&Instruction::Mov(GpRegister::R0, Value::Imm(0x8000)), Width::W16).is_none());
+        assert!(encode(&Instruction::Mov(GpRegister::R0, Value::Imm(0x8000)), Width::W32).is_some());
+    }
+
+    #[test]
+    fn rejects_stray_bits() {
+        use op::*;
+
+        // 16-bit words: [op] [a] [b] [flags] [slot 0] [slot 1], and each
+        // case differs from a legal instruction by one field
+        let cases: &[(&str, [u8; 8], [u8; 8])] = &[
+            ("flag on push's unused slot", [PUSH, 0, 0, 0b01, 5, 0, 0, 0], [PUSH, 0, 0, 0b11, 5, 0, 0, 0]),
+            ("flag on not's unused slot", [NOT, 1, 0, 0, 0, 0, 0, 0], [NOT, 1, 0, 0b01, 0, 0, 0, 0]),
+            ("flag on creturn", [CRETURN, 0, 0, 0, 0, 0, 0, 0], [CRETURN, 0, 0, 0b10, 0, 0, 0, 0]),
+            ("data in push's unused slot", [PUSH, 0, 0, 0b01, 5, 0, 0, 0], [PUSH, 0, 0, 0b01, 5, 0, 1, 0]),
+
+            ("b on not", [NOT, 1, 0, 0, 0, 0, 0, 0], [NOT, 1, 1, 0, 0, 0, 0, 0]),
+            ("b on pop", [POP, 2, 0, 0, 0, 0, 0, 0], [POP, 2, 3, 0, 0, 0, 0, 0]),
+            ("b on input", [INPUT, 1, 0, 0, 0, 0, 0, 0], [INPUT, 1, 1, 0, 0, 0, 0, 0]),
+            ("b on cause", [CAUSE, 1, 0, 0, 0, 0, 0, 0], [CAUSE, 1, 1, 0, 0, 0, 0, 0]),
+            ("b on cjmp", [CJMP, 1, 0, 0, 0, 0, 0, 0], [CJMP, 1, 1, 0, 0, 0, 0, 0]),
+            ("b on cpushcap", [CPUSHCAP, 1, 0, 0, 0, 0, 0, 0], [CPUSHCAP, 1, 2, 0, 0, 0, 0, 0]),
+
+            ("gp register in a", [MOV, 7, 0, 0b01, 5, 0, 0, 0], [MOV, 8, 0, 0b01, 5, 0, 0, 0]),
+            ("capability register in a", [CJMP, 7, 0, 0, 0, 0, 0, 0], [CJMP, 8, 0, 0, 0, 0, 0, 0]),
+            ("capability register in b", [LOAD, 1, 7, 0b01, 5, 0, 0, 0], [LOAD, 1, 8, 0b01, 5, 0, 0, 0]),
+            ("condition in b", [COND, 1, 4, 0b01, 3, 0, 0, 0], [COND, 1, 5, 0b01, 3, 0, 0, 0]),
+            ("special register in a", [CWRITESPECIAL, 6, 1, 0, 0, 0, 0, 0], [CWRITESPECIAL, 7, 1, 0, 0, 0, 0, 0]),
+            ("special register in b", [CREADSPECIAL, 1, 6, 0, 0, 0, 0, 0], [CREADSPECIAL, 1, 7, 0, 0, 0, 0, 0]),
+            ("gp register in slot 0", [PUSH, 0, 0, 0, 7, 0, 0, 0], [PUSH, 0, 0, 0, 8, 0, 0, 0]),
+            ("gp register in slot 1", [STORE, 1, 0, 0, 0, 0, 7, 0], [STORE, 1, 0, 0, 0, 0, 8, 0]),
+        ];
+
+        for (case, legal, stray) in cases {
+            assert!(decode(legal, Width::W16).is_some(), "{case}: the legal form doesn't decode");
+            assert!(decode(stray, Width::W16).is_none(), "{case}");
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt::Display;
+
+use crate::{bytecode::Width, capability::Permissions, reader::{Reader, Truncated}};
+
+pub const MAGIC: &[u8; 6] = b"CAPEMU";
+pub const VERSION: u8 = 2;
+
+/// A program image: the bytes to place at offset 0 of `DD`.
+///
+/// On disk it is `MAGIC`, the format `VERSION`, the word size in bytes,
+/// the grants as a u16 count + (name len u8 + name, offset u32, perms u8)*,
+/// then the encoded instructions and data.
+pub struct Image {
+    pub width: Width,
+    pub grants: Vec<Grant>,
+    pub bytes: Vec<u8>,
+}
+
+/// A capability the loader writes into the program, from `.cap`. Until
+/// then its slot holds the start and end of the region as two words.
+#[derive(Clone)]
+pub struct Grant {
+    pub name: String,
+    /// Where the slot is
+    pub offset: usize,
+    pub perms: Permissions,
+}
+
+impl Grant {
+    pub fn read(r: &mut Reader) -> Result<Self, Truncated> {
+        let name = r.name()?;
+        let offset = r.u32()? as usize;
+        Ok(Self { name, offset, perms: r.u8()?.into() })
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(self.name.len() as u8);
+        out.extend(self.name.as_bytes());
+        out.extend((self.offset as u32).to_le_bytes());
+        out.push(self.perms.into());
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    BadMagic,
+    Truncated,
+    UnsupportedVersion(u8),
+    WordSize(u8),
+    /// The slot for this grant runs past the end of the image
+    GrantOutside(String),
+}
+
+impl Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::BadMagic => write!(f, "not a cap-emu image"),
+            ImageError::Truncated => write!(f, "image is truncated"),
+            ImageError::UnsupportedVersion(v) => write!(f, "unsupported image version {v}"),
+            ImageError::WordSize(n) => write!(f, "unsupported word size of {n} bytes"),
+            ImageError::GrantOutside(name) => write!(f, "the slot for capability `{name}` is outside the image"),
+        }
+    }
+}
+
+impl From<Truncated> for ImageError {
+    fn from(_: Truncated) -> Self {
+        ImageError::Truncated
+    }
+}
+
+impl Image {
+    pub fn is_image(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    pub fn read(data: &[u8]) -> Result<Self, ImageError> {
+        let rest = data.strip_prefix(MAGIC).ok_or(ImageError::BadMagic)?;
+        let mut r = Reader::new(rest);
+
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(ImageError::UnsupportedVersion(version))
+        }
+        let width = Width::try_from(r.u8()?).map_err(ImageError::WordSize)?;
+
+        let mut grants = Vec::new();
+        for _ in 0..r.u16()? {
+            grants.push(Grant::read(&mut r)?);
+        }
+
+        let bytes = r.rest().to_vec();
+        if let Some(grant) = grants.iter().find(|g| g.offset.saturating_add(width.cap_size()) > bytes.len()) {
+            return Err(ImageError::GrantOutside(grant.name.clone()))
+        }
+        Ok(Self { width, grants, bytes })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(self.width.bytes() as u8);
+
+        out.extend((self.grants.len() as u16).to_le_bytes());
+        for grant in &self.grants {
+            grant.write(&mut out);
+        }
+
+        out.extend_from_slice(&self.bytes);
+        out
+    }
+}
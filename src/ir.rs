@@ -1,54 +1,218 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{cell::RefCell, collections::HashMap, fmt::{Debug, Display}};
 
-use crate::bytecode::{Instruction, Value, Int, GpRegister, Condition, CRegister};
+use crate::bytecode::{Instruction, Value, Int, GpRegister, Condition, CRegister, SpecialRegister, Width};
+use crate::capability::Permissions;
+use crate::diagnostic::Location;
 
 pub struct Env<'a> {
+    /// Labels, and imports standing in as 0
     pub map: &'a HashMap<String, Int>,
-    pub position: Int
+    /// `.equ` constants, which stay put when the module moves
+    pub constants: &'a HashMap<String, Int>,
+    /// Labels another module defines, which move independently of this one
+    pub imports: &'a [String],
+    pub width: Width,
+    pub position: Int,
+    /// Where each `Value` operand came from, in operand order, so a linker
+    /// can move the instruction
+    pub origins: &'a RefCell<Vec<Origin>>,
+}
+pub type IrInstruction = Box<dyn FnOnce(Env<'_>) -> Result<Instruction, ConvertError>>;
+
+#[derive(Debug)]
+pub enum ConvertError {
+    UndefinedLabel(String),
+    /// A bare name that isn't a `.equ` constant
+    UndefinedConstant(String),
+    DivisionByZero,
+    Overflow,
+    /// A hex or binary literal with more bits than a word
+    TooWide(u64),
+}
+
+impl Display for ConvertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConvertError::UndefinedLabel(name) => write!(f, "undefined label `{name}`"),
+            ConvertError::UndefinedConstant(name) => write!(f, "`{name}` isn't a `.equ` constant; a label is written `#{name}`"),
+            ConvertError::DivisionByZero => write!(f, "division by zero"),
+            ConvertError::Overflow => write!(f, "arithmetic overflow"),
+            ConvertError::TooWide(n) => write!(f, "{n:#x} doesn't fit in a word"),
+        }
+    }
 }
-pub type IrInstruction = Box<dyn FnOnce(Env<'_>) -> Result<Instruction, String>>;
 
 pub enum InterRep {
     Instruction(IrInstruction),
     Label(String),
+    /// Makes a label visible to other modules
+    Export(String),
+    /// Names a label another module defines
+    Import(String),
+    /// A named constant, used like a label
+    Equ(String, InterRepValue),
+    /// Raw bytes, from `.byte`, `.ascii`, `.asciz` and `.space`
+    Bytes(Vec<u8>),
+    /// One word for each value, from `.word`
+    Words(Vec<InterRepValue>),
+    /// Zero padding up to the next multiple of this many bytes
+    Align(usize),
+    /// A capability the loader grants, in a slot of its own, from `.cap`
+    Cap(String, CapLiteral),
+}
+
+/// A capability written out in source as `[start, end) perms`.
+#[derive(Clone)]
+pub struct CapLiteral {
+    pub start: InterRepValue,
+    pub end: InterRepValue,
+    pub perms: Permissions,
+}
+
+/// An `InterRep` and where in the source it was written.
+pub struct Statement {
+    pub ir: InterRep,
+    pub at: Location,
+}
+
+/// What an operand's value was computed from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+    Absolute,
+    Label(String),
+    Here,
+    /// Worked out from addresses in a way moving the module would upset,
+    /// like `#a * 2`
+    Computed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+/// What `sizeof` measures.
+#[derive(Debug, Clone, Copy)]
+pub enum Unit {
+    Instr,
+    Word,
 }
 
 #[derive(Debug, Clone)]
 pub enum InterRepValue {
     ByteCodeValue(Value),
+    /// A hex or binary literal, whose top bit is the sign of a word as wide
+    /// as the one it ends up in
+    Bits(u64),
     LabelRef(String),
-    Here
+    /// A `.equ` constant named without a `#`
+    Constant(String),
+    Here,
+    SizeOf(Unit),
+    Neg(Box<InterRepValue>),
+    Binary(Op, Box<InterRepValue>, Box<InterRepValue>),
+}
+
+impl InterRepValue {
+    /// Works out a constant expression, and what the linker would have to
+    /// do to keep it right. Subtracting one address from another gives a
+    /// plain number, which only holds for labels in the same module.
+    fn eval(&self, env: &Env) -> Result<(Int, Origin), ConvertError> {
+        let local = |origin: &Origin| match origin {
+            Origin::Here => true,
+            Origin::Label(name) => !env.imports.contains(name),
+            _ => false,
+        };
+        Ok(match self {
+            InterRepValue::ByteCodeValue(Value::Imm(n)) => (*n, Origin::Absolute),
+            InterRepValue::Bits(n) => {
+                let shift = u64::BITS - env.width.bits();
+                if n << shift >> shift != *n {
+                    return Err(ConvertError::TooWide(*n))
+                }
+                ((*n << shift) as Int >> shift, Origin::Absolute)
+            },
+            InterRepValue::ByteCodeValue(Value::Reg(_)) => unreachable!("registers can't be part of an expression"),
+            InterRepValue::LabelRef(name) => match (env.constants.get(name), env.map.get(name)) {
+                (Some(n), _) => (*n, Origin::Absolute),
+                (None, Some(n)) => (*n, Origin::Label(name.clone())),
+                (None, None) => return Err(ConvertError::UndefinedLabel(name.clone())),
+            },
+            InterRepValue::Constant(name) => match env.constants.get(name) {
+                Some(n) => (*n, Origin::Absolute),
+                None => return Err(ConvertError::UndefinedConstant(name.clone())),
+            },
+            InterRepValue::Here => (env.position, Origin::Here),
+            InterRepValue::SizeOf(Unit::Instr) => (env.width.instr_size() as Int, Origin::Absolute),
+            InterRepValue::SizeOf(Unit::Word) => (env.width.bytes() as Int, Origin::Absolute),
+            InterRepValue::Neg(value) => match value.eval(env)? {
+                (n, Origin::Absolute) => (n.checked_neg().ok_or(ConvertError::Overflow)?, Origin::Absolute),
+                (n, _) => (n.checked_neg().ok_or(ConvertError::Overflow)?, Origin::Computed),
+            },
+            InterRepValue::Binary(op, a, b) => {
+                let (a, a_origin) = a.eval(env)?;
+                let (b, b_origin) = b.eval(env)?;
+                let n = match op {
+                    Op::Add => a.checked_add(b),
+                    Op::Sub => a.checked_sub(b),
+                    Op::Mul => a.checked_mul(b),
+                    Op::Div if b == 0 => return Err(ConvertError::DivisionByZero),
+                    Op::Div => a.checked_div(b),
+                };
+                let origin = match (op, a_origin, b_origin) {
+                    (_, Origin::Absolute, Origin::Absolute) => Origin::Absolute,
+                    (Op::Add, origin, Origin::Absolute) | (Op::Add, Origin::Absolute, origin) => origin,
+                    (Op::Sub, origin, Origin::Absolute) => origin,
+                    (Op::Sub, a, b) if local(&a) && local(&b) => Origin::Absolute,
+                    _ => Origin::Computed,
+                };
+                (n.ok_or(ConvertError::Overflow)?, origin)
+            },
+        })
+    }
 }
 
 pub trait Convert<T> {
-    fn convert(&self, labels: &Env) -> Result<T, String>;
+    fn convert(&self, labels: &Env) -> Result<T, ConvertError>;
 }
 
 impl Convert<Value> for InterRepValue {
-    fn convert(&self, labels: &Env) -> Result<Value, String> {
-        match self {
-            InterRepValue::ByteCodeValue(bcv) => Ok(*bcv),
-            InterRepValue::LabelRef(lab) => 
-                labels.map.get(lab).map(|n| Value::Imm(*n)).ok_or_else(||lab.clone()),
-            InterRepValue::Here => Ok(Value::Imm(labels.position))
-        }
+    fn convert(&self, labels: &Env) -> Result<Value, ConvertError> {
+        let (value, origin) = match self {
+            InterRepValue::ByteCodeValue(Value::Reg(r)) => (Value::Reg(*r), Origin::Absolute),
+            value => {
+                let (n, origin) = value.eval(labels)?;
+                (Value::Imm(n), origin)
+            },
+        };
+        labels.origins.borrow_mut().push(origin);
+        Ok(value)
     }
 }
 
 impl Convert<GpRegister> for GpRegister {
-    fn convert(&self, _: &Env) -> Result<GpRegister, String> {
+    fn convert(&self, _: &Env) -> Result<GpRegister, ConvertError> {
         Ok(*self)
     }
 }
 
 impl Convert<CRegister> for CRegister {
-    fn convert(&self, _: &Env) -> Result<CRegister, String> {
+    fn convert(&self, _: &Env) -> Result<CRegister, ConvertError> {
         Ok(*self)
     }
 }
 
 impl Convert<Condition> for Condition {
-    fn convert(&self, _: &Env) -> Result<Condition, String> {
+    fn convert(&self, _: &Env) -> Result<Condition, ConvertError> {
+        Ok(*self)
+    }
+}
+
+impl Convert<SpecialRegister> for SpecialRegister {
+    fn convert(&self, _: &Env) -> Result<SpecialRegister, ConvertError> {
         Ok(*self)
     }
 }
\ No newline at end of file
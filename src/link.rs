@@ -0,0 +1,106 @@
+//! Combines assembled modules into one image.
+//!
+//! Each module is placed at a base address, either the one asked for or the
+//! first one after the module before it that keeps its alignment. The image is loaded at offset 0 of
+//! `DD` as usual, so execution starts at whatever sits at 0: normally the
+//! first module.
+
+use std::{collections::HashMap, fmt::Display, ops::Range};
+
+use crate::{bytecode::{Int, Width}, image::{Grant, Image}, object::{Object, Target}};
+
+pub struct Module {
+    /// For error messages, usually the path it came from
+    pub name: String,
+    pub object: Object,
+    pub base: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum LinkError {
+    /// The module was built for another word size
+    Width(String),
+    Overlap(String, String),
+    /// The base asked for isn't a multiple of the module's alignment
+    Misaligned { module: String, align: usize },
+    DuplicateSymbol(String),
+    UndefinedSymbol { module: String, symbol: String },
+    /// A relocated word no longer fits
+    Overflow { module: String, offset: usize },
+}
+
+impl Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkError::Width(module) => write!(f, "{module}: built for a different word size"),
+            LinkError::Overlap(a, b) => write!(f, "{a} and {b} overlap"),
+            LinkError::Misaligned { module, align } => write!(f, "{module} has to be placed at a multiple of {align}"),
+            LinkError::DuplicateSymbol(symbol) => write!(f, "`{symbol}` is exported more than once"),
+            LinkError::UndefinedSymbol { module, symbol } => write!(f, "{module}: undefined symbol `{symbol}`"),
+            LinkError::Overflow { module, offset } =>
+                write!(f, "{module}: relocated word at {offset:04x} doesn't fit"),
+        }
+    }
+}
+
+/// Links `modules` into an image, returning it with every exported symbol's
+/// address.
+pub fn link(modules: &[Module], width: Width) -> Result<(Image, HashMap<String, Int>), LinkError> {
+    let mut placed: Vec<Range<usize>> = Vec::new();
+    let mut next: usize = 0;
+    for module in modules {
+        if module.object.width != width {
+            return Err(LinkError::Width(module.name.clone()))
+        }
+
+        let align = module.object.align;
+        let base = module.base.unwrap_or(next.next_multiple_of(align));
+        if base % align != 0 {
+            return Err(LinkError::Misaligned { module: module.name.clone(), align })
+        }
+        let range = base..base + module.object.code.len();
+        if let Some(n) = placed.iter().position(|other| range.start < other.end && other.start < range.end) {
+            return Err(LinkError::Overlap(modules[n].name.clone(), module.name.clone()))
+        }
+
+        next = range.end;
+        placed.push(range);
+    }
+
+    let mut symbols = HashMap::new();
+    for (module, range) in modules.iter().zip(&placed) {
+        for (name, offset) in &module.object.exports {
+            if symbols.insert(name.clone(), range.start as Int + offset).is_some() {
+                return Err(LinkError::DuplicateSymbol(name.clone()))
+            }
+        }
+    }
+
+    let mut bytes = vec![0; placed.iter().map(|range| range.end).max().unwrap_or(0)];
+    let mut grants = Vec::new();
+    for (module, range) in modules.iter().zip(&placed) {
+        grants.extend(module.object.grants.iter().map(|grant| Grant { offset: range.start + grant.offset, ..grant.clone() }));
+
+        let code = &mut bytes[range.clone()];
+        code.copy_from_slice(&module.object.code);
+
+        for reloc in &module.object.relocs {
+            let delta = match &reloc.target {
+                Target::Base => range.start as Int,
+                Target::Import(symbol) => *symbols.get(symbol).ok_or_else(|| LinkError::UndefinedSymbol {
+                    module: module.name.clone(),
+                    symbol: symbol.clone(),
+                })?,
+            };
+
+            let at = reloc.offset;
+            let value = width.read(&code[at..]) + delta;
+            if !width.fits(value) {
+                return Err(LinkError::Overflow { module: module.name.clone(), offset: range.start + at })
+            }
+            width.write(value, &mut code[at..]);
+        }
+    }
+
+    Ok((Image { width, grants, bytes }, symbols))
+}
@@ -0,0 +1,134 @@
+//! Loads an image with the least authority it needs, instead of running it
+//! on the root capability.
+//!
+//! RAM is laid out as the image's code from address 0, then a data segment
+//! and the stack, each starting on a capability granule. Every capability
+//! keeps its pointer at 0, so labels and the PC are still plain addresses,
+//! but the bounds cover only its own segment:
+//!
+//! - `CC`: the code, `r-x`
+//! - `DD`: data and stack, `rw-`, with `SP` at the top of the stack
+//! - `C1`: the device windows, `rw-`
+//!
+//! `C0` keeps its sealing authority. Everything else, the root included, is
+//! gone by the time the program runs.
+//!
+//! Before that, each `.cap` the image asks for is derived from the root and
+//! written into its slot, so a program can still be handed, say, a bounded
+//! view of a table inside its own code. It gets no more than `CC` has over
+//! the same bytes.
+
+use std::{fmt::Display, ops::Range};
+
+use crate::{bytecode::{CRegister, GpRegister, Int}, capability::{Capability, Inner, Permissions, Seal}, image::Image, vm::{Machine, Memory}};
+
+/// Sizes of the segments the image doesn't supply.
+#[derive(Debug, Clone, Copy)]
+pub struct Segments {
+    pub data: usize,
+    pub stack: usize,
+}
+
+impl Default for Segments {
+    fn default() -> Self {
+        Self { data: 0, stack: 512 }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    /// The segments need this many bytes of RAM
+    TooLarge(usize),
+    /// The slot for this `.cap` can't hold a capability where it ended up
+    Misaligned(String),
+    /// This `.cap` asks for more than the image
+    OutsideImage(String),
+    /// This `.cap` asks for sealing or privilege, or to be writable and
+    /// executable at once
+    Permissions(String),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::TooLarge(n) => write!(f, "segments need {n} bytes, more than there is memory"),
+            LoadError::Misaligned(name) => write!(f, "the slot for capability `{name}` isn't capability-aligned"),
+            LoadError::OutsideImage(name) => write!(f, "capability `{name}` reaches outside the image"),
+            LoadError::Permissions(name) => write!(f, "capability `{name}` asks for permissions a program can't be granted"),
+        }
+    }
+}
+
+/// Copies `image` into the freshly built `machine` and swaps its root
+/// capabilities for per-segment ones.
+pub fn load(machine: &mut Machine, image: &Image, segments: Segments) -> Result<(), LoadError> {
+    let memory = &mut machine.memory;
+    let format = memory.format();
+    let cap_size = memory.cap_size();
+
+    let text = format.round(0..image.bytes.len() as Int);
+    let len = (segments.data + segments.stack) as Int;
+
+    // compressed bounds round outward, so slide the segment up until
+    // rounding no longer pulls it over the code
+    let mut start = (text.end as usize).next_multiple_of(cap_size) as Int;
+    let mut data = format.round(start..start + len);
+    while data.start < text.end {
+        start += cap_size as Int;
+        data = format.round(start..start + len);
+    }
+    if data.end as usize > memory.ram_size() {
+        return Err(LoadError::TooLarge(data.end as usize))
+    }
+
+    let root = machine.reg[CRegister::DD];
+    memory.store_slice(root, 0, &image.bytes).expect("the code fits in RAM");
+    let code = Permissions::rwx(true, false, true);
+    grant(memory, root, code, image)?;
+
+    let cap = |bounds: Range<Int>, perms: Permissions| Capability {
+        inner: Inner::new(0, bounds, perms, Seal::Unsealed),
+        valid: true,
+    };
+    let devices = format.round(memory.ram_size() as Int..memory.size() as Int);
+
+    machine.reg[CRegister::CC] = cap(text, code);
+    machine.reg[CRegister::DD] = cap(data.clone(), Permissions::rwx(true, true, false));
+    machine.reg[CRegister::C1] = cap(devices, Permissions::rwx(true, true, false));
+    machine.reg[GpRegister::SP] = data.end;
+    Ok(())
+}
+
+/// Fills in the capabilities `image` asks for with `.cap`, once it has been
+/// copied to address 0 under `root`. Each points at the start of its region,
+/// can't cover more than the image, and has at most the permissions of
+/// `segment`, whatever holds the image once it runs.
+pub fn grant(memory: &mut Memory, root: Capability, segment: Permissions, image: &Image) -> Result<(), LoadError> {
+    let width = memory.width();
+    for grant in &image.grants {
+        if grant.offset % memory.cap_size() != 0 {
+            return Err(LoadError::Misaligned(grant.name.clone()))
+        }
+
+        let Some(slot) = image.bytes.get(grant.offset..grant.offset + memory.cap_size()) else {
+            return Err(LoadError::OutsideImage(grant.name.clone()))
+        };
+        let start = width.read(slot);
+        let end = width.read(&slot[width.bytes()..]);
+        if start < 0 || start > end || end > image.bytes.len() as Int {
+            return Err(LoadError::OutsideImage(grant.name.clone()))
+        }
+
+        let asked = grant.perms;
+        if asked.seal || asked.unseal || asked.privileged || (asked.write && asked.exec) {
+            return Err(LoadError::Permissions(grant.name.clone()))
+        }
+        let perms = u8::from(asked) & u8::from(segment) & u8::from(root.inner.perms());
+        let cap = Capability {
+            inner: Inner::new(start, memory.format().round(start..end), perms.into(), Seal::Unsealed),
+            valid: true,
+        };
+        memory.store_cap(root, grant.offset as Int, cap).map_err(|_| LoadError::OutsideImage(grant.name.clone()))?;
+    }
+    Ok(())
+}
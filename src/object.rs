@@ -0,0 +1,212 @@
+//! Relocatable object files: one assembled module, before the linker has
+//! decided where it lives.
+//!
+//! Code is assembled as if the module sat at offset 0. Every word, in an
+//! instruction or in `.word` data, that came from a label or `.` gets a
+//! relocation, so the linker can add the module's base, or the address of an
+//! imported symbol, once it knows them.
+//!
+//! On disk it is `MAGIC`, the format `VERSION`, the word size in bytes, then:
+//!
+//! ```text
+//! alignment u32,
+//! code: len u32 + bytes,
+//! exports: count u16 + (name len u8 + name, offset u64)*,
+//! imports: count u16 + (name len u8 + name)*,
+//! relocs: count u32 + (offset u32, import index u16)*,
+//! grants: count u16 + (name len u8 + name, offset u32, perms u8)*
+//! ```
+//!
+//! Integers are little-endian. An import index of `0xffff` relocates
+//! against the module's own base.
+
+use std::fmt::Display;
+
+use crate::{bytecode::{Int, Width}, image::Grant, reader::{Reader, Truncated}};
+
+pub const MAGIC: &[u8; 6] = b"CAPOBJ";
+pub const VERSION: u8 = 4;
+
+const BASE: u16 = 0xffff;
+
+/// What a relocated word is measured from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    /// The start of this module
+    Base,
+    /// A symbol another module exports
+    Import(String),
+}
+
+/// A word the linker has to adjust.
+#[derive(Debug, Clone)]
+pub struct Reloc {
+    /// Byte offset of the word within the code
+    pub offset: usize,
+    pub target: Target,
+}
+
+pub struct Object {
+    pub width: Width,
+    /// What the base has to be a multiple of for `.align` and `.cap` slots
+    /// to still line up
+    pub align: usize,
+    pub code: Vec<u8>,
+    /// Labels visible to other modules, as offsets into the code
+    pub exports: Vec<(String, Int)>,
+    pub relocs: Vec<Reloc>,
+    /// Capabilities for the loader to fill in, at offsets into the code
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Debug)]
+pub enum ObjectError {
+    BadMagic,
+    Truncated,
+    UnsupportedVersion(u8),
+    WordSize(u8),
+    Alignment(u32),
+    BadImport(u16),
+    /// A relocated word or a grant's slot at this offset runs past the code
+    OutsideCode(usize),
+    TrailingBytes,
+}
+
+impl Display for ObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectError::BadMagic => write!(f, "not a cap-emu object"),
+            ObjectError::Truncated => write!(f, "object is truncated"),
+            ObjectError::UnsupportedVersion(v) => write!(f, "unsupported object version {v}"),
+            ObjectError::WordSize(n) => write!(f, "unsupported word size of {n} bytes"),
+            ObjectError::Alignment(n) => write!(f, "bad alignment of {n} bytes"),
+            ObjectError::BadImport(n) => write!(f, "relocation against missing import {n}"),
+            ObjectError::OutsideCode(offset) => write!(f, "offset {offset:04x} is past the end of the code"),
+            ObjectError::TrailingBytes => write!(f, "object has trailing bytes"),
+        }
+    }
+}
+
+impl From<Truncated> for ObjectError {
+    fn from(_: Truncated) -> Self {
+        ObjectError::Truncated
+    }
+}
+
+impl Object {
+    pub fn is_object(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Every symbol the relocations need from other modules.
+    pub fn imports(&self) -> Vec<&str> {
+        let mut imports = Vec::new();
+        for reloc in &self.relocs {
+            if let Target::Import(name) = &reloc.target {
+                if !imports.contains(&name.as_str()) {
+                    imports.push(name.as_str());
+                }
+            }
+        }
+        imports
+    }
+
+    pub fn read(data: &[u8]) -> Result<Self, ObjectError> {
+        let rest = data.strip_prefix(MAGIC).ok_or(ObjectError::BadMagic)?;
+        let mut r = Reader::new(rest);
+
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(ObjectError::UnsupportedVersion(version))
+        }
+        let width = Width::try_from(r.u8()?).map_err(ObjectError::WordSize)?;
+        let align = match r.u32()? {
+            0 => return Err(ObjectError::Alignment(0)),
+            n => n as usize,
+        };
+
+        let len = r.u32()? as usize;
+        let code = r.take(len)?.to_vec();
+
+        let mut exports = Vec::new();
+        for _ in 0..r.u16()? {
+            exports.push((r.name()?, r.int()?));
+        }
+
+        let mut imports = Vec::new();
+        for _ in 0..r.u16()? {
+            imports.push(r.name()?);
+        }
+
+        let mut relocs = Vec::new();
+        for _ in 0..r.u32()? {
+            let offset = r.u32()? as usize;
+            if offset + width.bytes() > code.len() {
+                return Err(ObjectError::OutsideCode(offset))
+            }
+            let target = match r.u16()? {
+                BASE => Target::Base,
+                n => Target::Import(imports.get(n as usize).ok_or(ObjectError::BadImport(n))?.clone()),
+            };
+            relocs.push(Reloc { offset, target });
+        }
+
+        let mut grants = Vec::new();
+        for _ in 0..r.u16()? {
+            let grant = Grant::read(&mut r)?;
+            if grant.offset + width.cap_size() > code.len() {
+                return Err(ObjectError::OutsideCode(grant.offset))
+            }
+            grants.push(grant);
+        }
+
+        if !r.is_empty() {
+            return Err(ObjectError::TrailingBytes)
+        }
+        Ok(Self { width, align, code, exports, relocs, grants })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.push(self.width.bytes() as u8);
+        out.extend((self.align as u32).to_le_bytes());
+
+        out.extend((self.code.len() as u32).to_le_bytes());
+        out.extend(&self.code);
+
+        let name = |out: &mut Vec<u8>, name: &str| {
+            out.push(name.len() as u8);
+            out.extend(name.as_bytes());
+        };
+
+        out.extend((self.exports.len() as u16).to_le_bytes());
+        for (export, offset) in &self.exports {
+            name(&mut out, export);
+            out.extend(offset.to_le_bytes());
+        }
+
+        let imports = self.imports();
+        out.extend((imports.len() as u16).to_le_bytes());
+        for import in &imports {
+            name(&mut out, import);
+        }
+
+        out.extend((self.relocs.len() as u32).to_le_bytes());
+        for reloc in &self.relocs {
+            out.extend((reloc.offset as u32).to_le_bytes());
+            let target = match &reloc.target {
+                Target::Base => BASE,
+                Target::Import(import) => imports.iter().position(|i| i == import).unwrap() as u16,
+            };
+            out.extend(target.to_le_bytes());
+        }
+
+        out.extend((self.grants.len() as u16).to_le_bytes());
+        for grant in &self.grants {
+            grant.write(&mut out);
+        }
+
+        out
+    }
+}
@@ -1,3 +1,6 @@
+// pom's combinators lean on `*`/`-` binding tighter than `|`
+#![allow(clippy::precedence)]
+
 use pom::parser::*;
 
 use crate::bytecode::CRegister;
@@ -5,14 +8,21 @@ use crate::bytecode::Instruction;
 use crate::bytecode::Value;
 use crate::bytecode::Condition;
 use crate::bytecode::GpRegister;
+use crate::bytecode::SpecialRegister;
 use crate::bytecode::Int;
 use crate::ir::InterRep;
 use crate::ir::InterRepValue;
+use crate::ir::Op;
+use crate::ir::CapLiteral;
+use crate::capability::Permissions;
+use crate::ir::Unit;
 use crate::ir::IrInstruction;
 use crate::ir::Convert;
 use crate::ir::Env;
+use crate::ir::Statement;
+use crate::diagnostic::{Diagnostic, Line};
 
-fn gp_reg<'a>() -> Parser<'a, u8, GpRegister> {
+pub fn gp_reg<'a>() -> Parser<'a, u8, GpRegister> {
     seq(b"r0").map(|_|GpRegister::R0)
     | seq(b"r1").map(|_|GpRegister::R1)
     | seq(b"r2").map(|_|GpRegister::R2)
@@ -23,7 +33,7 @@ fn gp_reg<'a>() -> Parser<'a, u8, GpRegister> {
     | seq(b"sp").map(|_|GpRegister::SP)
 }
 
-fn c_reg<'a>() -> Parser<'a, u8, CRegister> {
+pub fn c_reg<'a>() -> Parser<'a, u8, CRegister> {
     seq(b"c0").map(|_|CRegister::C0)
     | seq(b"c1").map(|_|CRegister::C1)
     | seq(b"c2").map(|_|CRegister::C2)
@@ -34,28 +44,196 @@ fn c_reg<'a>() -> Parser<'a, u8, CRegister> {
     | seq(b"dd").map(|_|CRegister::DD)
 }
 
+fn special_reg<'a>() -> Parser<'a, u8, SpecialRegister> {
+    seq(b"tcc").map(|_|SpecialRegister::Tcc)
+    | seq(b"epcc").map(|_|SpecialRegister::Epcc)
+    | seq(b"ecap").map(|_|SpecialRegister::Ecap)
+    | seq(b"ivec").map(|_|SpecialRegister::Ivec)
+    | seq(b"idd").map(|_|SpecialRegister::Idd)
+    | seq(b"ipcc").map(|_|SpecialRegister::Ipcc)
+    | seq(b"ipdd").map(|_|SpecialRegister::Ipdd)
+}
+
+fn escape<'a>() -> Parser<'a, u8, u8> {
+    sym(b'\\') * (
+        sym(b'n').map(|_| b'\n')
+        | sym(b't').map(|_| b'\t')
+        | sym(b'r').map(|_| b'\r')
+        | sym(b'0').map(|_| 0)
+        | one_of(b"\\\"'")
+    )
+}
+
+/// `0x` hex or `0b` binary digits. In an operand they're the bits of a
+/// word, so `0xffff` is -1 when words are 16 bits.
+fn radix<'a>(prefix: &'static [u8], radix: u32) -> Parser<'a, u8, u64> {
+    let digits = is_a(move |n: u8| (n as char).is_digit(radix)).repeat(1..);
+    seq(prefix) * digits.collect().convert(move |s| {
+        let s = String::from_utf8_lossy(s);
+        let prefix = String::from_utf8_lossy(prefix);
+        u64::from_str_radix(&s, radix).map_err(|_| format!("{prefix}{s} doesn't fit in a word"))
+    })
+}
+
+fn bits<'a>() -> Parser<'a, u8, u64> {
+    radix(b"0x", 16) | radix(b"0b", 2)
+}
+
 fn number<'a>() -> Parser<'a, u8, Int> {
+    let character = sym(b'\'') * (none_of(b"\\'") | escape()) - sym(b'\'');
+    let unsigned = bits().map(|n| n as Int) | character.map(Int::from);
+    let signed = (sym(b'-').opt() + unsigned).map(|(minus, n)| match minus {
+        Some(_) => n.wrapping_neg(),
+        None => n,
+    });
+
     let integer = one_of(b"0123456789").repeat(1..);
-	let number = sym(b'-').opt() + integer;
-	number.collect().convert(std::str::from_utf8).convert(|s| s.parse())
+	let decimal = sym(b'-').opt() + integer;
+	signed | decimal.collect().convert(|s| {
+	    let s = String::from_utf8_lossy(s);
+	    s.parse().map_err(|_| format!("{s} doesn't fit in a word"))
+	})
+}
+
+fn is_ident(n: u8) -> bool {
+    n.is_ascii_alphanumeric() || n == b'_'
+}
+
+/// `word` on its own, not the start of a longer name.
+fn keyword<'a>(word: &'static str) -> Parser<'a, u8, ()> {
+    seq(word.as_bytes()).discard() - !is_a(is_ident)
 }
 
 fn label<'a>() -> Parser<'a, u8, String> {
-    let string = is_a(|n: u8| n.is_ascii() && (n as char).is_alphabetic()).repeat(1..);
+    let first = is_a(|n: u8| n.is_ascii_alphabetic() || n == b'_');
+    let rest = is_a(is_ident).repeat(0..);
 
-    string.convert(String::from_utf8)
+    (first + rest).collect().convert(|s| String::from_utf8(s.to_vec()))
 }
 
-fn value<'a>() -> Parser<'a, u8, InterRepValue> {
-    gp_reg().map(|r| InterRepValue::ByteCodeValue(Value::Reg(r)))
+/// A number, `#label`, `.`, `sizeof(instr)` or `sizeof(word)`, or a
+/// parenthesised expression. A `.equ` constant can be written as a label or
+/// by its bare name.
+fn atom<'a>() -> Parser<'a, u8, InterRepValue> {
+    let unit = keyword("instr").map(|_| Unit::Instr) | keyword("word").map(|_| Unit::Word);
+
+    bits().map(InterRepValue::Bits)
     | number().map(|n| InterRepValue::ByteCodeValue(Value::Imm(n)))
-    | (sym(b'#') * label()).map(|s| InterRepValue::LabelRef(s))
+    | (sym(b'#') * label()).map(InterRepValue::LabelRef)
     | sym(b'.').map(|_| InterRepValue::Here)
+    | (keyword("sizeof") * space() * sym(b'(') * space() * unit - space() - sym(b')')).map(InterRepValue::SizeOf)
+    | sym(b'(') * space() * call(constant) - space() - sym(b')')
+    | (sym(b'-') * call(atom)).map(|v| InterRepValue::Neg(Box::new(v)))
+    | label().map(InterRepValue::Constant)
+}
+
+/// One of `ops`, with space on both sides or neither, since operands are
+/// themselves separated by spaces: `store dd 8 -1` is two operands.
+fn operator<'a>(ops: &'static [u8]) -> Parser<'a, u8, Op> {
+    let blank = || one_of(b" \t").repeat(1..);
+    let op = || one_of(ops).map(|op| match op {
+        b'+' => Op::Add,
+        b'-' => Op::Sub,
+        b'*' => Op::Mul,
+        _ => Op::Div,
+    });
+    blank() * op() - blank() | op()
+}
+
+/// Applies each operator in turn, left to right.
+fn fold(first: InterRepValue, rest: Vec<(Op, InterRepValue)>) -> InterRepValue {
+    rest.into_iter().fold(first, |a, (op, b)| InterRepValue::Binary(op, Box::new(a), Box::new(b)))
+}
+
+/// A value known once labels are: anything but a register. Constant
+/// expressions are worked out when the program is laid out.
+fn constant<'a>() -> Parser<'a, u8, InterRepValue> {
+    let term = || (atom() + (operator(b"*/") + atom()).repeat(0..)).map(|(a, rest)| fold(a, rest));
+    (term() + (operator(b"+-") + term()).repeat(0..)).map(|(a, rest)| fold(a, rest))
+}
+
+fn value<'a>() -> Parser<'a, u8, InterRepValue> {
+    // a register, unless it's the start of a constant's name like `sp_top`
+    (gp_reg() - !is_a(is_ident)).map(|r| InterRepValue::ByteCodeValue(Value::Reg(r)))
+    | constant()
+}
+
+fn string<'a>() -> Parser<'a, u8, Vec<u8>> {
+    sym(b'"') * (none_of(b"\\\"") | escape()).repeat(0..) - sym(b'"')
+}
+
+/// A non-negative count, for `.space` and `.align`.
+fn count<'a>() -> Parser<'a, u8, usize> {
+    number().convert(|n| usize::try_from(n).map_err(|_| format!("{n} can't be negative")))
+}
+
+fn bytes(numbers: Vec<Int>) -> Result<Vec<u8>, String> {
+    numbers.into_iter().map(|n| match n {
+        -128..=255 => Ok(n as u8),
+        _ => Err(format!("{n} doesn't fit in a byte")),
+    }).collect()
+}
+
+/// Comma-separated items of a directive.
+fn items<'a, T: 'a>(item: Parser<'a, u8, T>) -> Parser<'a, u8, Vec<T>> {
+    list(item, space() * sym(b',') * space())
+}
+
+/// Directive `name`, which once seen must be followed by `args`.
+fn dir<'a, T: 'a>(name: &'static str, expected: &'static str, args: Parser<'a, u8, T>) -> Parser<'a, u8, T> {
+    keyword(name) * space() * args.expect(expected)
+}
+
+/// Permission letters as capabilities print them, in any order, with `-`
+/// allowed as a placeholder: `rw`, `r-x`.
+fn perms<'a>() -> Parser<'a, u8, Permissions> {
+    let letters = one_of(b"rwxsup-").repeat(1..) - !is_a(is_ident);
+    letters.map(|letters| {
+        let mut perms = Permissions::rwx(false, false, false);
+        for letter in letters {
+            match letter {
+                b'r' => perms.read = true,
+                b'w' => perms.write = true,
+                b'x' => perms.exec = true,
+                b's' => perms.seal = true,
+                b'u' => perms.unseal = true,
+                b'p' => perms.privileged = true,
+                _ => (),
+            }
+        }
+        perms
+    })
+}
+
+/// `[start, end) perms`
+fn cap_literal<'a>() -> Parser<'a, u8, CapLiteral> {
+    let bounds = sym(b'[') * space() * (constant() - space() - sym(b',') - space()) + constant() - space() - sym(b')');
+    (bounds - space() + perms()).map(|((start, end), perms)| CapLiteral { start, end, perms })
+}
+
+fn directive<'a>() -> Parser<'a, u8, InterRep> {
+    let comma = || space() * sym(b',') * space();
+    let cap = (label() - comma()) + (constant() - comma()) + (constant() - comma()) + perms();
+    dir(".export", "a label to export", label()).map(InterRep::Export)
+    | dir(".import", "a label to import", label()).map(InterRep::Import)
+    | dir(".equ", "a name and a value", (label() - space()) + constant()).map(|(name, value)| InterRep::Equ(name, value))
+    | dir(".byte", "a list of bytes", items(number()).convert(bytes)).map(InterRep::Bytes)
+    | dir(".word", "a list of words", items(constant())).map(InterRep::Words)
+    | dir(".asciz", "a string", string()).map(|mut s| {
+        s.push(0);
+        InterRep::Bytes(s)
+    })
+    | dir(".ascii", "a string", string()).map(InterRep::Bytes)
+    | dir(".space", "a byte count", count()).map(|n| InterRep::Bytes(vec![0; n]))
+    | dir(".align", "an alignment", count()).map(InterRep::Align)
+    | dir(".cap", "a name, start, end and permissions", cap).map(|(((name, start), end), perms)| {
+        InterRep::Cap(name, CapLiteral { start, end, perms })
+    })
 }
 
 fn cond<'a>() -> Parser<'a, u8, Condition> {
-    seq(b"<").map(|_|Condition::L)
-    | seq(b"<=").map(|_|Condition::LE)
+    seq(b"<=").map(|_|Condition::LE)
+    | seq(b"<").map(|_|Condition::L)
     | seq(b"==").map(|_|Condition::E)
     | seq(b">=").map(|_|Condition::GE)
     | seq(b">").map(|_|Condition::G)
@@ -65,23 +243,34 @@ fn space<'a>() -> Parser<'a, u8, ()> {
 	one_of(b" \t\r\n").repeat(0..).discard()
 }
 
+/// A `;` or `//` comment, which runs to the end of the line.
+fn comment<'a>() -> Parser<'a, u8, ()> {
+    (sym(b';').discard() | seq(b"//").discard()) * any().repeat(0..).discard()
+}
+
 macro_rules! instr {
+    ($gen:expr, $name:ident) => {
+        keyword(stringify!($name)).map(|_| {
+            Box::new(move |_: Env| Ok($gen)) as IrInstruction
+        })
+    };
+
     ($gen:expr, $name:ident, $a:expr) => {
-        seq(stringify!($name).as_bytes()) * space() * $a.map(|l| {
+        keyword(stringify!($name)) * space() * $a.map(|l| {
             Box::new(move |labels: Env| Ok($gen(l.convert(&labels)?))) as IrInstruction
-        })
+        }).expect(concat!("operands for `", stringify!($name), "`"))
     };
 
     ($gen:expr, $name:ident, $a:expr, $b:expr) => {
-        seq(stringify!($name).as_bytes()) * space() * (($a - space()) + $b).map(|(l, r)| {
+        keyword(stringify!($name)) * space() * (($a - space()) + $b).map(|(l, r)| {
             Box::new(move |labels: Env| Ok($gen(l.convert(&labels)?, r.convert(&labels)?))) as IrInstruction
-        })
+        }).expect(concat!("operands for `", stringify!($name), "`"))
     };
 
     ($gen:expr, $name:ident, $a:expr, $b:expr, $c:expr) => {
-        seq(stringify!($name).as_bytes()) * space() * (($a - space()) + ($b - space()) + $c).map(|((l, c), r)| {
+        keyword(stringify!($name)) * space() * (($a - space()) + ($b - space()) + $c).map(|((l, c), r)| {
             Box::new(move |labels: Env| Ok($gen(l.convert(&labels)?, c.convert(&labels)?, r.convert(&labels)?))) as IrInstruction
-        })
+        }).expect(concat!("operands for `", stringify!($name), "`"))
     };
 }
 
@@ -110,21 +299,130 @@ fn instruction<'a>() -> Parser<'a, u8, IrInstruction> {
     | instr!(Cond, cond, gp_reg(), cond(), value())
 
     | instr!(Emit, emit, value())
+    | instr!(Input, input, gp_reg())
+    | instr!(Halt, halt, value())
+
+    | instr!(CLoadCap, cloadcap, c_reg(), c_reg(), value())
+    | instr!(CStoreCap, cstorecap, c_reg(), value(), c_reg())
+    | instr!(CLoad, cload, gp_reg(), c_reg())
+    | instr!(CStore, cstore, value(), c_reg())
+    | instr!(CJmp, cjmp, c_reg())
+
+    | instr!(CPushCap, cpushcap, c_reg())
+    | instr!(CPopCap, cpopcap, c_reg())
+
+    | instr!(CInvoke, cinvoke, c_reg(), c_reg())
+    | instr!(CCall, ccall, c_reg(), c_reg())
+    | instr!(CReturn, creturn)
+    | instr!(CSeal, cseal, c_reg(), c_reg())
+    | instr!(CUnseal, cunseal, c_reg(), c_reg())
+
+    | instr!(CSetBounds, csetbounds, c_reg(), c_reg(), value())
+    | instr!(CAndPerm, candperm, c_reg(), c_reg(), value())
+    | instr!(CIncOffset, cincoffset, c_reg(), c_reg(), value())
+    | instr!(|c, mask| CAndPerm(c, c, mask), crestrict, c_reg(), value())
+    | instr!(|dest, src| CIncOffset(dest, src, Value::Imm(0)), cmove, c_reg(), c_reg())
+
+    | instr!(CReadSpecial, cspecialr, c_reg(), special_reg())
+    | instr!(CWriteSpecial, cspecialw, special_reg(), c_reg())
+    | instr!(Cause, cause, gp_reg())
+    | instr!(TRet, tret)
+
+    | instr!(Ei, ei)
+    | instr!(Di, di)
+    | instr!(IRet, iret)
+    | instr!(IpcRead, ipcr, gp_reg())
+    | instr!(IpcWrite, ipcw, value())
+}
+
+/// `cderive dest src [start, end) perms` derives a capability over just
+/// that region from `src`, pointing at `start`. The bounds are offsets from
+/// `src`'s pointer, which are addresses for the usual capabilities with a
+/// pointer of 0. It stands for a `cincoffset`, a `csetbounds` and a
+/// `candperm`.
+fn derive<'a>() -> Parser<'a, u8, Vec<InterRep>> {
+    use Instruction::*;
+
+    let operands = (c_reg() - space()) + (c_reg() - space()) + cap_literal();
+    (keyword("cderive") * space() * operands.expect("operands for `cderive`")).map(|((dest, src), cap)| {
+        let CapLiteral { start, end, perms } = cap;
+        let len = InterRepValue::Binary(Op::Sub, Box::new(end), Box::new(start.clone()));
+        let mask = Value::Imm(u8::from(perms).into());
+
+        let instrs: [IrInstruction; 3] = [
+            Box::new(move |env: Env| Ok(CIncOffset(dest, src, start.convert(&env)?))),
+            Box::new(move |env: Env| Ok(CSetBounds(dest, dest, len.convert(&env)?))),
+            Box::new(move |_: Env| Ok(CAndPerm(dest, dest, mask))),
+        ];
+        instrs.into_iter().map(InterRep::Instruction).collect()
+    })
 }
 
-fn statement<'a>() -> Parser<'a, u8, InterRep> {
-    instruction().map(|i| InterRep::Instruction(i))
-    | (label() - sym(b':')).map(InterRep::Label)
+fn statement<'a>() -> Parser<'a, u8, Vec<InterRep>> {
+    derive()
+    | instruction().map(|i| vec![InterRep::Instruction(i)])
+    | (label() - sym(b':')).map(|l| vec![InterRep::Label(l)])
+    | directive().map(|d| vec![d])
 }
 
+/// Turns a parse error on `line` into something readable. `start` is where
+/// the statement that failed began.
+fn diagnose(line: &Line, start: usize, error: pom::Error) -> Diagnostic {
+    let position = |error: &pom::Error| match error {
+        pom::Error::Incomplete => line.text.len(),
+        pom::Error::Mismatch { position, .. }
+        | pom::Error::Conversion { position, .. }
+        | pom::Error::Expect { position, .. }
+        | pom::Error::Custom { position, .. } => *position,
+    };
 
-fn program<'a>() -> Parser<'a, u8, Vec<InterRep>> {
-    // list(instruction(), space())
-    // instruction().map(|i| vec![i])
-    space() * (statement() - space()).repeat(0..) - end()
+    match error {
+        pom::Error::Expect { message, inner, .. } => match *inner {
+            pom::Error::Conversion { message, position } => {
+                // pom wraps our message up as `Conversion error: "..."`
+                let message = message.strip_prefix("Conversion error: \"")
+                    .and_then(|m| m.strip_suffix('"'))
+                    .unwrap_or(&message);
+                Diagnostic::error(line.at(position + 1), message)
+            },
+            inner => Diagnostic::error(line.at(position(&inner) + 1), message.replacen("Expect", "expected", 1)),
+        },
+        _ => {
+            let token = line.text[start..].split(char::is_whitespace).next().unwrap_or_default();
+            Diagnostic::error(line.at(start + 1), format!("expected an instruction, label or directive, found `{token}`"))
+        },
+    }
 }
 
-pub fn parse(input: &str) -> Result<Vec<InterRep>, pom::Error> {
-    let bytes = input.as_bytes();
-    program().parse(bytes)
+/// Parses every line, reporting each one that doesn't parse rather than
+/// stopping at the first.
+pub fn parse(lines: &[Line]) -> Result<Vec<Statement>, Vec<Diagnostic>> {
+    let statement = statement();
+    let space = space() - comment().opt();
+
+    let mut statements = Vec::new();
+    let mut errors = Vec::new();
+    for line in lines {
+        let bytes = line.text.as_bytes();
+        let skip = |pos| space.parse_at(bytes, pos).map_or(pos, |(_, pos)| pos);
+
+        let mut pos = skip(0);
+        while pos < bytes.len() {
+            match statement.parse_at(bytes, pos) {
+                Ok((irs, next)) => {
+                    statements.extend(irs.into_iter().map(|ir| Statement { ir, at: line.at(pos + 1) }));
+                    pos = skip(next);
+                },
+                Err(e) => {
+                    errors.push(diagnose(line, pos, e));
+                    break
+                },
+            }
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(statements),
+        false => Err(errors),
+    }
 }
@@ -0,0 +1,205 @@
+//! Textual expansion that runs before the parser: `.include` pulls in other
+//! source files, and `.macro`/`.endm` define multi-line macros.
+//!
+//! ```text
+//! .macro save a, b
+//!     push \a
+//!     push \b
+//! .endm
+//!     save r1, r2
+//! ```
+//!
+//! A macro is invoked by putting its name at the start of a line, followed
+//! by comma-separated arguments. In the body, `\name` is replaced by the
+//! argument for that parameter and `\@` by a number unique to the
+//! expansion, for labels that mustn't clash between uses.
+
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, rc::Rc};
+
+use crate::diagnostic::{Diagnostic, Line};
+
+/// How deep macros may expand inside one another before it's taken to be
+/// unbounded recursion.
+const MAX_DEPTH: usize = 64;
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+#[derive(Default)]
+struct Preprocessor {
+    macros: HashMap<String, Macro>,
+    /// Files being expanded, innermost last
+    includes: Vec<PathBuf>,
+    /// Macro expansions so far, for `\@`
+    expansions: usize,
+    out: Vec<Line>,
+    errors: Vec<Diagnostic>,
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Splits `text` at the start of a `;` or `//` comment, if it has one
+/// outside a string or character literal.
+fn split_comment(text: &str) -> (&str, &str) {
+    let bytes = text.as_bytes();
+    let mut quote = None;
+    let mut n = 0;
+    while n < bytes.len() {
+        match (quote, bytes[n]) {
+            (Some(_), b'\\') => n += 1,
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => (),
+            (None, c @ (b'"' | b'\'')) => quote = Some(c),
+            (None, b';') => return text.split_at(n),
+            (None, b'/') if bytes.get(n + 1) == Some(&b'/') => return text.split_at(n),
+            (None, _) => (),
+        }
+        n += 1;
+    }
+    (text, "")
+}
+
+impl Preprocessor {
+    fn file(&mut self, path: &Path, source: &str) {
+        self.includes.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+
+        let file: Rc<str> = path.display().to_string().into();
+        let lines = source.lines().enumerate().map(|(n, text)| Line {
+            file: file.clone(),
+            number: n + 1,
+            text: text.into(),
+            expansion: None,
+        });
+        self.lines(lines.collect(), 0);
+
+        self.includes.pop();
+    }
+
+    fn lines(&mut self, lines: Vec<Line>, depth: usize) {
+        let mut lines = lines.into_iter();
+        while let Some(line) = lines.next() {
+            let trimmed = split_comment(&line.text).0.trim();
+            let (word, rest) = trimmed.split_once(char::is_whitespace).unwrap_or((trimmed, ""));
+            let rest = rest.trim();
+
+            match word {
+                ".macro" => {
+                    let mut words = rest.split(|c: char| c == ',' || c.is_whitespace()).filter(|w| !w.is_empty());
+                    let Some(name) = words.next().filter(|name| name.chars().all(is_ident)) else {
+                        self.errors.push(Diagnostic::error(line.start(), "expected `.macro name [params]`"));
+                        continue
+                    };
+                    let params = words.map(String::from).collect();
+
+                    let mut body = Vec::new();
+                    loop {
+                        match lines.next() {
+                            Some(next) if split_comment(&next.text).0.trim() == ".endm" => break,
+                            Some(next) => body.push(next.text.to_string()),
+                            None => {
+                                self.errors.push(Diagnostic::error(line.start(), format!("macro `{name}` has no `.endm`")));
+                                return
+                            },
+                        }
+                    }
+                    self.macros.insert(name.into(), Macro { params, body });
+                },
+                ".endm" => self.errors.push(Diagnostic::error(line.start(), "`.endm` outside a macro")),
+                ".include" => {
+                    let Some(file) = rest.strip_prefix('"').and_then(|r| r.strip_suffix('"')) else {
+                        self.errors.push(Diagnostic::error(line.start(), "expected `.include \"file\"`"));
+                        continue
+                    };
+                    let dir = Path::new(&*line.file).parent().unwrap_or(Path::new(""));
+                    let path = dir.join(file);
+
+                    let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+                    if self.includes.contains(&canonical) {
+                        self.errors.push(Diagnostic::error(line.start(), format!("{} includes itself", path.display())));
+                        continue
+                    }
+                    match fs::read_to_string(&path) {
+                        Ok(source) => self.file(&path, &source),
+                        Err(e) => self.errors.push(Diagnostic::error(line.start(), format!("{}: {e}", path.display()))),
+                    }
+                },
+                name if self.macros.contains_key(name) => {
+                    let args: Vec<&str> = match rest {
+                        "" => Vec::new(),
+                        _ => rest.split(',').map(str::trim).collect(),
+                    };
+                    if depth >= MAX_DEPTH {
+                        self.errors.push(Diagnostic::error(line.start(), format!("macro `{name}` expands too deeply")));
+                        return
+                    }
+                    match self.substitute(name, &args) {
+                        Ok(body) => {
+                            let expansion: Rc<str> = name.into();
+                            let body = body.into_iter().map(|text| Line {
+                                text: text.into(),
+                                expansion: Some(expansion.clone()),
+                                ..line.clone()
+                            });
+                            self.lines(body.collect(), depth + 1);
+                        },
+                        Err(message) => self.errors.push(Diagnostic::error(line.start(), message)),
+                    }
+                },
+                _ => self.out.push(line),
+            }
+        }
+    }
+
+    /// The body of macro `name` with `args` filled in.
+    fn substitute(&mut self, name: &str, args: &[&str]) -> Result<Vec<String>, String> {
+        let mac = &self.macros[name];
+        if args.len() != mac.params.len() {
+            return Err(format!("macro `{name}` takes {} arguments, found {}", mac.params.len(), args.len()))
+        }
+
+        self.expansions += 1;
+        let mut body = Vec::new();
+        for line in &mac.body {
+            let mut out = String::new();
+            let (mut rest, comment) = split_comment(line);
+            while let Some(at) = rest.find('\\') {
+                out.push_str(&rest[..at]);
+                rest = &rest[at + 1..];
+
+                if let Some(after) = rest.strip_prefix('@') {
+                    out.push_str(&self.expansions.to_string());
+                    rest = after;
+                    continue
+                }
+
+                let end = rest.find(|c| !is_ident(c)).unwrap_or(rest.len());
+                let param = &rest[..end];
+                let n = mac.params.iter().position(|p| p == param)
+                    .ok_or_else(|| format!("macro `{name}` has no parameter `{param}`"))?;
+                out.push_str(args[n]);
+                rest = &rest[end..];
+            }
+            out.push_str(rest);
+            out.push_str(comment);
+            body.push(out);
+        }
+
+        Ok(body)
+    }
+}
+
+/// Expands the includes and macros in `source`, which was read from `path`.
+/// Included files are found relative to the file that includes them.
+pub fn expand(source: &str, path: &Path) -> Result<Vec<Line>, Vec<Diagnostic>> {
+    let mut pre = Preprocessor::default();
+    pre.file(path, source);
+
+    match pre.errors.is_empty() {
+        true => Ok(pre.out),
+        false => Err(pre.errors),
+    }
+}
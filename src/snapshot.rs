@@ -0,0 +1,215 @@
+//! Whole-machine snapshots, so a run can be stopped, written to disk and
+//! resumed later exactly where it left off.
+//!
+//! A snapshot is `MAGIC`, the format `VERSION`, then:
+//!
+//! ```text
+//! config: word size u8, compressed u8, memory size u64,
+//! ticks u64, halted: u8 + code i64,
+//! registers: gp i64 x 8, caps cap x 8, pc i64, special cap x 7,
+//!            cause i64, in trap u8, interrupt enable u8, saved pc i64,
+//! trusted stack: count u8 + (cap x 8, r4-r6 i64 x 3, sp i64, pc i64)*,
+//! ram: memory size bytes, tags: one bit per granule,
+//! devices: count u8 + (name len u8 + name, state len u32 + state)*
+//! ```
+//!
+//! Integers are little-endian, and a capability is its valid flag followed
+//! by its four fields as i64, whatever the machine's own format. The console,
+//! any tracer and the undo journal aren't part of the machine's state and are
+//! left out.
+
+use std::fmt::Display;
+
+use crate::{bytecode::{Width, GP_REGISTERS}, capability::{Capability, CapFormat, Inner}, switcher::Frame, vm::{Config, ConfigError, Machine}, reader::{Reader, Truncated}};
+
+pub const MAGIC: &[u8; 7] = b"CAPSNAP";
+pub const VERSION: u8 = 2;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    BadMagic,
+    Truncated,
+    UnsupportedVersion(u8),
+    WordSize(u8),
+    Config(ConfigError),
+    /// The snapshot has this many devices, not the standard set
+    DeviceCount(usize),
+    /// The snapshot's device here doesn't match the standard one
+    Device(String),
+    TrailingBytes,
+}
+
+impl From<Truncated> for SnapshotError {
+    fn from(_: Truncated) -> Self {
+        SnapshotError::Truncated
+    }
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a cap-emu snapshot"),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {v}"),
+            SnapshotError::WordSize(n) => write!(f, "unsupported word size of {n} bytes"),
+            SnapshotError::Config(e) => write!(f, "{e}"),
+            SnapshotError::DeviceCount(n) => write!(f, "snapshot has {n} devices, which doesn't match this machine"),
+            SnapshotError::Device(name) => write!(f, "snapshot state for device `{name}` doesn't fit this machine"),
+            SnapshotError::TrailingBytes => write!(f, "snapshot has trailing bytes"),
+        }
+    }
+}
+
+pub fn is_snapshot(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn write_cap(out: &mut Vec<u8>, cap: Capability) {
+    out.push(cap.valid as u8);
+    out.extend(cap.inner.to_bytes(CapFormat::Full, Width::W64));
+}
+
+pub fn write(machine: &Machine) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+
+    let memory = &machine.memory;
+    out.push(machine.width().bytes() as u8);
+    out.push(matches!(memory.format(), CapFormat::Compressed(_)) as u8);
+    out.extend((memory.mem.len() as u64).to_le_bytes());
+
+    out.extend(machine.ticks.to_le_bytes());
+    out.push(machine.halted.is_some() as u8);
+    out.extend(machine.halted.unwrap_or_default().to_le_bytes());
+
+    let reg = &machine.reg;
+    for n in reg.gp {
+        out.extend(n.to_le_bytes());
+    }
+    for cap in reg.cap {
+        write_cap(&mut out, cap);
+    }
+    out.extend(reg.pc.to_le_bytes());
+    for cap in reg.special {
+        write_cap(&mut out, cap);
+    }
+    out.extend(reg.cause.to_le_bytes());
+    out.push(reg.in_trap as u8);
+    out.push(reg.interrupts as u8);
+    out.extend(reg.ipc.to_le_bytes());
+
+    out.push(machine.trusted_stack.frames.len() as u8);
+    for frame in &machine.trusted_stack.frames {
+        for cap in frame.cap {
+            write_cap(&mut out, cap);
+        }
+        for n in frame.saved {
+            out.extend(n.to_le_bytes());
+        }
+        out.extend(frame.sp.to_le_bytes());
+        out.extend(frame.pc.to_le_bytes());
+    }
+
+    out.extend(&memory.mem);
+    out.extend(&memory.cap_tags);
+
+    out.push(memory.bus.iter().count() as u8);
+    for (_, device) in memory.bus.iter() {
+        out.push(device.name().len() as u8);
+        out.extend(device.name().as_bytes());
+        let state = device.save();
+        out.extend((state.len() as u32).to_le_bytes());
+        out.extend(state);
+    }
+
+    out
+}
+
+fn read_cap(r: &mut Reader) -> Result<Capability, Truncated> {
+    let valid = r.u8()? != 0;
+    let inner = Inner::from_bytes(r.take(32)?, CapFormat::Full, Width::W64);
+    Ok(Capability { inner, valid })
+}
+
+fn read_caps<const N: usize>(r: &mut Reader) -> Result<[Capability; N], Truncated> {
+    let mut caps = [Capability::default(); N];
+    for cap in &mut caps {
+        *cap = read_cap(r)?;
+    }
+    Ok(caps)
+}
+
+/// Rebuilds a machine from a snapshot. It gets the standard devices and the
+/// default console, which the caller can swap out.
+pub fn read(data: &[u8]) -> Result<Machine, SnapshotError> {
+    let rest = data.strip_prefix(MAGIC).ok_or(SnapshotError::BadMagic)?;
+    let mut r = Reader::new(rest);
+
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version))
+    }
+
+    let word = r.u8()?;
+    let width = Width::try_from(word).map_err(SnapshotError::WordSize)?;
+    let compressed = r.u8()? != 0;
+    let memory_size = r.u64()? as usize;
+    let config = Config { memory_size, width, compressed };
+
+    // RAM and its tags come later, so the snapshot has to be at least that
+    // long before it's worth allocating them
+    let tags = (memory_size / config.cap_format().cap_size(width)).div_ceil(8);
+    if memory_size.checked_add(tags).is_none_or(|len| len > r.len()) {
+        return Err(SnapshotError::Truncated)
+    }
+    let mut machine = Machine::new(config).map_err(SnapshotError::Config)?;
+
+    machine.ticks = r.u64()?;
+    let halted = r.u8()? != 0;
+    let code = r.int()?;
+    machine.halted = halted.then_some(code);
+
+    let reg = &mut machine.reg;
+    for n in &mut reg.gp {
+        *n = r.int()?;
+    }
+    reg.cap = read_caps(&mut r)?;
+    reg.pc = r.int()?;
+    reg.special = read_caps(&mut r)?;
+    reg.cause = r.int()?;
+    reg.in_trap = r.u8()? != 0;
+    reg.interrupts = r.u8()? != 0;
+    reg.ipc = r.int()?;
+
+    let depth = r.u8()?;
+    for _ in 0..depth {
+        let cap = read_caps::<GP_REGISTERS>(&mut r)?;
+        let saved = [r.int()?, r.int()?, r.int()?];
+        let frame = Frame { cap, saved, sp: r.int()?, pc: r.int()? };
+        machine.trusted_stack.frames.push(frame);
+    }
+
+    let memory = &mut machine.memory;
+    let len = memory.mem.len();
+    memory.mem.copy_from_slice(r.take(len)?);
+    let len = memory.cap_tags.len();
+    memory.cap_tags.copy_from_slice(r.take(len)?);
+
+    let count = r.u8()? as usize;
+    if count != memory.bus.iter().count() {
+        return Err(SnapshotError::DeviceCount(count))
+    }
+    for device in memory.bus.iter_mut() {
+        let name = r.name()?;
+        let len = r.u32()? as usize;
+        let state = r.take(len)?;
+        if name != device.name() || !device.restore(state) {
+            return Err(SnapshotError::Device(name))
+        }
+    }
+
+    if !r.is_empty() {
+        return Err(SnapshotError::TrailingBytes)
+    }
+    Ok(machine)
+}
@@ -0,0 +1,35 @@
+use crate::{bytecode::{Int, GP_REGISTERS}, capability::Capability, vm::RuntimeError};
+
+pub const MAX_DEPTH: usize = 16;
+
+/// Caller state saved by `CCall` and put back by `CReturn`.
+#[derive(Clone, Copy)]
+pub struct Frame {
+    pub cap: [Capability; GP_REGISTERS],
+    /// `R4`-`R6`, which the callee starts without
+    pub saved: [Int; 3],
+    pub sp: Int,
+    pub pc: Int,
+}
+
+/// The switcher's stack of suspended callers. It lives outside guest memory,
+/// so no compartment can read or forge the saved capabilities.
+#[derive(Default, Clone)]
+pub struct TrustedStack {
+    pub(crate) frames: Vec<Frame>,
+}
+
+impl TrustedStack {
+    pub fn push(&mut self, frame: Frame) -> Result<(), RuntimeError> {
+        if self.frames.len() >= MAX_DEPTH {
+            return Err(RuntimeError::TrustedStackOverflow)
+        }
+
+        self.frames.push(frame);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<Frame, RuntimeError> {
+        self.frames.pop().ok_or(RuntimeError::TrustedStackUnderflow)
+    }
+}
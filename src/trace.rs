@@ -0,0 +1,248 @@
+//! Per-tick execution traces, for golden tests of guest programs and for
+//! finding where two emulator builds diverge.
+//!
+//! Every tick produces one `Entry`: the instruction at the PC and everything
+//! it changed. Entries are written as text, as JSON Lines, or in a compact
+//! binary form that starts with `MAGIC`, the format `VERSION` and the word
+//! size, followed by the entries back to back:
+//!
+//! ```text
+//! tick: u64, pc: i64, instr: len u8 + encoding,
+//! regs: count u8 + (id u8, int i64 | valid u8 + four i64 fields)*,
+//! changes: count u16 + (0 u8, addr u64, len u16, old, new | 1 u8, addr u64, valid u8)*,
+//! event: kind u8 + arg i64
+//! ```
+//!
+//! Integers are little-endian. Register ids are `0x00 + n` for `rn`,
+//! `0x10 + n` for capability registers, `0x20 + n` for special registers,
+//! then `0x30` for the cause, `0x31` for the saved PC and `0x32` for the
+//! interrupt enable.
+
+use std::{fmt::Write as _, io::{self, Write}};
+
+use crate::{bytecode::{Instruction, Int, Width, GpRegister, CRegister, SpecialRegister}, capability::{Capability, CapFormat}, encoding, vm::Change};
+
+pub const MAGIC: &[u8; 8] = b"CAPTRACE";
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Reg {
+    Gp(GpRegister),
+    Cap(CRegister),
+    Special(SpecialRegister),
+    Cause,
+    Ipc,
+    Ie,
+}
+
+impl Reg {
+    fn id(self) -> u8 {
+        match self {
+            Reg::Gp(r) => r as u8,
+            Reg::Cap(c) => 0x10 + c as u8,
+            Reg::Special(s) => 0x20 + s as u8,
+            Reg::Cause => 0x30,
+            Reg::Ipc => 0x31,
+            Reg::Ie => 0x32,
+        }
+    }
+
+    fn name(self) -> String {
+        match self {
+            Reg::Gp(r) => r.to_string(),
+            Reg::Cap(c) => c.to_string(),
+            Reg::Special(s) => s.to_string(),
+            Reg::Cause => "cause".into(),
+            Reg::Ipc => "ipc".into(),
+            Reg::Ie => "ie".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegValue {
+    Int(Int),
+    Cap(Capability),
+}
+
+/// Anything besides the instruction itself that happened during the tick.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// The fault was delivered to the trap handler
+    Trap { cause: Int },
+    Interrupt { irq: usize },
+    Halt { code: Int },
+    /// The fault escaped to the host
+    Fault { cause: Int },
+}
+
+impl Event {
+    fn kind(self) -> (u8, Int) {
+        match self {
+            Event::Trap { cause } => (1, cause),
+            Event::Interrupt { irq } => (2, irq as Int),
+            Event::Halt { code } => (3, code),
+            Event::Fault { cause } => (4, cause),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub tick: u64,
+    pub pc: Int,
+    /// `None` when the fetch itself faulted
+    pub instr: Option<Instruction>,
+    pub regs: Vec<(Reg, RegValue)>,
+    pub changes: Vec<Change>,
+    pub event: Option<Event>,
+}
+
+pub struct Tracer {
+    format: Format,
+    width: Width,
+    out: Box<dyn Write>,
+    /// The first write that failed; later entries are dropped
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(format: Format, width: Width, out: impl Write + 'static) -> Self {
+        let mut tracer = Self { format, width, out: Box::new(out), error: None };
+        if format == Format::Binary {
+            let header = [MAGIC.as_slice(), &[VERSION, width.bytes() as u8]].concat();
+            tracer.emit(&header);
+        }
+        tracer
+    }
+
+    pub fn record(&mut self, entry: &Entry) {
+        let bytes = match self.format {
+            Format::Text => Self::text(entry).into_bytes(),
+            Format::Json => Self::json(entry).into_bytes(),
+            Format::Binary => self.binary(entry),
+        };
+        self.emit(&bytes);
+    }
+
+    /// Flushes the trace, reporting the first write that failed.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        if self.error.is_none() {
+            self.error = self.out.write_all(bytes).err();
+        }
+    }
+
+    fn text(entry: &Entry) -> String {
+        let mut out = match entry.instr {
+            Some(instr) => format!("{:>6} {:04x}  {instr}\n", entry.tick, entry.pc),
+            None => format!("{:>6} {:04x}  ??\n", entry.tick, entry.pc),
+        };
+        match entry.event {
+            Some(Event::Trap { cause }) => writeln!(out, "       trap, cause {cause}"),
+            Some(Event::Interrupt { irq }) => writeln!(out, "       interrupt {irq}"),
+            _ => Ok(()),
+        }.unwrap();
+        out
+    }
+
+    fn json(entry: &Entry) -> String {
+        let mut out = format!("{{\"tick\":{},\"pc\":{}", entry.tick, entry.pc);
+
+        match entry.instr {
+            Some(instr) => write!(out, ",\"instr\":\"{instr}\""),
+            None => write!(out, ",\"instr\":null"),
+        }.unwrap();
+
+        let regs: Vec<String> = entry.regs.iter().map(|(reg, value)| match value {
+            RegValue::Int(n) => format!("\"{}\":{n}", reg.name()),
+            RegValue::Cap(cap) => format!(
+                "\"{}\":{{\"valid\":{},\"ptr\":{},\"base\":{},\"top\":{},\"perms\":\"{}\",\"otype\":{}}}",
+                reg.name(), cap.valid, cap.inner.ptr(), cap.inner.bounds().start, cap.inner.bounds().end,
+                cap.inner.perms(), u8::from(cap.inner.seal()),
+            ),
+        }).collect();
+        write!(out, ",\"regs\":{{{}}}", regs.join(",")).unwrap();
+
+        let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let mut mem = Vec::new();
+        let mut tags = Vec::new();
+        for change in &entry.changes {
+            match change {
+                Change::Bytes { addr, old, new } =>
+                    mem.push(format!("{{\"addr\":{addr},\"old\":\"{}\",\"new\":\"{}\"}}", hex(old), hex(new))),
+                Change::Tag { addr, valid } => tags.push(format!("{{\"addr\":{addr},\"valid\":{valid}}}")),
+            }
+        }
+        write!(out, ",\"mem\":[{}],\"tags\":[{}]", mem.join(","), tags.join(",")).unwrap();
+
+        match entry.event {
+            Some(Event::Trap { cause }) => write!(out, ",\"event\":{{\"trap\":{cause}}}"),
+            Some(Event::Interrupt { irq }) => write!(out, ",\"event\":{{\"interrupt\":{irq}}}"),
+            Some(Event::Halt { code }) => write!(out, ",\"event\":{{\"halt\":{code}}}"),
+            Some(Event::Fault { cause }) => write!(out, ",\"event\":{{\"fault\":{cause}}}"),
+            None => Ok(()),
+        }.unwrap();
+
+        out.push_str("}\n");
+        out
+    }
+
+    fn binary(&self, entry: &Entry) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(entry.tick.to_le_bytes());
+        out.extend(entry.pc.to_le_bytes());
+
+        let instr = entry.instr.and_then(|instr| encoding::encode(&instr, self.width)).unwrap_or_default();
+        out.push(instr.len() as u8);
+        out.extend(instr);
+
+        out.push(entry.regs.len() as u8);
+        for (reg, value) in &entry.regs {
+            out.push(reg.id());
+            match value {
+                RegValue::Int(n) => out.extend(n.to_le_bytes()),
+                RegValue::Cap(cap) => {
+                    out.push(cap.valid as u8);
+                    out.extend(cap.inner.to_bytes(CapFormat::Full, Width::W64));
+                },
+            }
+        }
+
+        out.extend((entry.changes.len() as u16).to_le_bytes());
+        for change in &entry.changes {
+            match change {
+                Change::Bytes { addr, old, new } => {
+                    out.push(0);
+                    out.extend((*addr as u64).to_le_bytes());
+                    out.extend((old.len() as u16).to_le_bytes());
+                    out.extend(old);
+                    out.extend(new);
+                },
+                Change::Tag { addr, valid } => {
+                    out.push(1);
+                    out.extend((*addr as u64).to_le_bytes());
+                    out.push(*valid as u8);
+                },
+            }
+        }
+
+        let (kind, arg) = entry.event.map_or((0, 0), Event::kind);
+        out.push(kind);
+        out.extend(arg.to_le_bytes());
+        out
+    }
+}
@@ -1,17 +1,56 @@
-use std::{ops::{IndexMut, Index}, mem::{align_of, size_of}, fmt::Display};
+use std::{collections::VecDeque, ops::{IndexMut, Index, Range}, fmt::Display, io, num::NonZeroU8};
 
-use crate::{bytecode::{Int, GpRegister, Instruction, Value, GP_REGISTERS, CRegister}, capability::{Capability, Inner, CAP_SIZE, Permissions, Seal}};
+use crate::{encoding, bytecode::{Int, Width, GpRegister, Instruction, Value, GP_REGISTERS, SPECIAL_REGISTERS, CRegister, SpecialRegister}, capability::{Capability, CapFormat, Inner, Permissions, Seal}, console::{self, Console, StdoutConsole}, device::{self, Bus, Device}, trace::{Entry, Event, Reg, RegValue, Tracer}, switcher::{TrustedStack, Frame}};
 
 pub struct Machine {
     pub memory: Memory,
-    pub reg: RegisterFile
+    pub reg: RegisterFile,
+    pub trusted_stack: TrustedStack,
+    /// The exit code, once the program has executed `Halt`.
+    pub halted: Option<Int>,
+    /// Ticks executed so far, including any that faulted
+    pub ticks: u64,
+    console: console::Handle,
+    tracer: Option<Tracer>,
+    history: Option<History>,
 }
 
-#[derive(Default)]
+/// What a tick overwrote, enough to put the machine back as it was.
+struct Undo {
+    reg: RegisterFile,
+    trusted_stack: TrustedStack,
+    halted: Option<Int>,
+    ticks: u64,
+    changes: Vec<Change>,
+}
+
+/// The undo journal, holding at most `limit` ticks.
+struct History {
+    undo: VecDeque<Undo>,
+    limit: usize,
+}
+
+#[derive(Debug)]
+pub enum RunOutcome {
+    Halted(Int),
+    Faulted { error: RuntimeError, pc: Int },
+    Exhausted,
+}
+
+#[derive(Default, Clone)]
 pub struct RegisterFile {
-    gp: [Int; GP_REGISTERS],
-    cap: [Capability; GP_REGISTERS],
-    pc: Int
+    pub(crate) gp: [Int; GP_REGISTERS],
+    pub(crate) cap: [Capability; GP_REGISTERS],
+    pub(crate) pc: Int,
+    pub(crate) special: [Capability; SPECIAL_REGISTERS],
+    pub(crate) cause: Int,
+    /// Set while a trap handler runs, so a fault inside it isn't re-trapped
+    pub(crate) in_trap: bool,
+    /// Whether device interrupts are delivered to `Ivec`
+    pub(crate) interrupts: bool,
+    /// The PC to resume at with `Ipcc`
+    pub(crate) ipc: Int,
+    width: Width,
 }
 
 impl Index<GpRegister> for RegisterFile {
@@ -42,216 +81,673 @@ impl IndexMut<CRegister> for RegisterFile {
     }
 }
 
+impl Index<SpecialRegister> for RegisterFile {
+    type Output = Capability;
+
+    fn index(&self, index: SpecialRegister) -> &Self::Output {
+        &self.special[index as usize]
+    }
+}
+
+impl IndexMut<SpecialRegister> for RegisterFile {
+    fn index_mut(&mut self, index: SpecialRegister) -> &mut Self::Output {
+        &mut self.special[index as usize]
+    }
+}
+
+impl RegisterFile {
+    pub fn pc(&self) -> Int {
+        self.pc
+    }
+
+    /// Every register that differs from `before`, besides the PC.
+    pub fn diff(&self, before: &RegisterFile) -> Vec<(Reg, RegValue)> {
+        let mut out = Vec::new();
+        for i in 0..GP_REGISTERS as u8 {
+            let gp = GpRegister::try_from(i).unwrap();
+            if self[gp] != before[gp] {
+                out.push((Reg::Gp(gp), RegValue::Int(self[gp])));
+            }
+        }
+        for i in 0..GP_REGISTERS as u8 {
+            let cap = CRegister::try_from(i).unwrap();
+            if self[cap] != before[cap] {
+                out.push((Reg::Cap(cap), RegValue::Cap(self[cap])));
+            }
+        }
+        for i in 0..SPECIAL_REGISTERS as u8 {
+            let s = SpecialRegister::try_from(i).unwrap();
+            if self[s] != before[s] {
+                out.push((Reg::Special(s), RegValue::Cap(self[s])));
+            }
+        }
+
+        let ints = [
+            (Reg::Cause, self.cause, before.cause),
+            (Reg::Ipc, self.ipc, before.ipc),
+            (Reg::Ie, self.interrupts as Int, before.interrupts as Int),
+        ];
+        for (reg, now, then) in ints {
+            if now != then {
+                out.push((reg, RegValue::Int(now)));
+            }
+        }
+
+        out
+    }
+}
+
 impl Display for RegisterFile {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for i in 0..GP_REGISTERS as u8 {
-            // safe because i in [0, GP_REGISTERS)
-            let gp: GpRegister = unsafe { std::mem::transmute(i) };
+            // infallible because i in [0, GP_REGISTERS)
+            let gp = GpRegister::try_from(i).unwrap();
 
-            write!(f, "{:?} {:04x}", gp, self[gp])?;
+            write!(f, "{:?} {:04x}", gp, self.width.unsigned(self[gp]))?;
 
-            let cap: CRegister = unsafe { std::mem::transmute(i) };
+            let cap = CRegister::try_from(i).unwrap();
             writeln!(f, "\t\t{:?} {:?}", cap, self[cap])?;
         }
-        writeln!(f, "PC {:04x} ({})", self.pc, self.pc as usize / size_of::<Instruction>())?;
+        writeln!(f, "PC {:04x} ({})", self.pc, self.pc as usize / self.width.instr_size())?;
+        for i in 0..SPECIAL_REGISTERS as u8 {
+            let s = SpecialRegister::try_from(i).unwrap();
+            writeln!(f, "{:?}\t{:?}", s, self[s])?;
+        }
+        writeln!(f, "Cause {:04x}", self.width.unsigned(self.cause))?;
+        writeln!(f, "IPC {:04x}", self.ipc)?;
+        writeln!(f, "IE {}", self.interrupts as u8)?;
         Ok(())
     }
 }
 
-const MEMORY_SIZE: usize = 4096;
+/// The shape of a machine, fixed when it's built.
+#[derive(Debug, Clone, Copy)]
+pub struct Config {
+    /// Bytes of physical memory
+    pub memory_size: usize,
+    pub width: Width,
+    /// Store capabilities in the compressed format, with rounded bounds
+    pub compressed: bool,
+}
+
+impl Config {
+    pub fn cap_format(&self) -> CapFormat {
+        match self.compressed {
+            true => CapFormat::compressed(self.width),
+            false => CapFormat::Full,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { memory_size: 4096, width: Width::W16, compressed: false }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Addresses past the end of memory wouldn't fit in a word
+    MemoryTooLarge,
+    /// Memory must be a whole number of capability granules
+    UnalignedMemorySize,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::MemoryTooLarge => write!(f, "memory is too large to address with the word size"),
+            ConfigError::UnalignedMemorySize => write!(f, "memory size must be a multiple of the capability size"),
+        }
+    }
+}
+
+/// One change a store made to RAM, kept while the memory is recording.
+/// Device state isn't captured.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Bytes { addr: usize, old: Vec<u8>, new: Vec<u8> },
+    /// The tag of the granule at `addr` became `valid`
+    Tag { addr: usize, valid: bool },
+}
 
-#[repr(align(4))]
 pub struct Memory {
-    mem: [u8; MEMORY_SIZE],
-    cap_tags: [u8; MEMORY_SIZE / CAP_SIZE / 8]
+    pub(crate) mem: Vec<u8>,
+    pub bus: Bus,
+    pub(crate) cap_tags: Vec<u8>,
+    width: Width,
+    format: CapFormat,
+    log: Option<Vec<Change>>,
 }
 
 impl Memory {
-    unsafe fn get_mut_ptr<T>(&self, addr: Int, count: usize) -> Result<*mut T, RuntimeError> {
-        let ptr = unsafe { self.mem.as_ptr().add(addr as usize) as *mut T };
+    /// Builds RAM of the configured size, with `devices` mapped one after
+    /// another above it, each starting on a capability granule.
+    pub fn new(config: Config, devices: Vec<Box<dyn Device>>) -> Result<Self, ConfigError> {
+        let cap_size = config.cap_format().cap_size(config.width);
+        if !config.memory_size.is_multiple_of(cap_size) {
+            return Err(ConfigError::UnalignedMemorySize)
+        }
+
+        let mut bus = Bus::default();
+        let mut base = config.memory_size;
+        for device in devices {
+            let size = device.size(config.width);
+            bus.map(base, device, config.width);
+            base = (base + size).next_multiple_of(cap_size);
+        }
 
-        let align = align_of::<T>();
-        if (ptr as usize) % align != 0 {
-            return Err(RuntimeError::UnalignedAccess { addr, align: align as Int })
+        if base as u64 > config.width.max() as u64 {
+            return Err(ConfigError::MemoryTooLarge)
         }
 
-        Ok(ptr)
+        Ok(Self {
+            mem: vec![0; config.memory_size],
+            bus,
+            cap_tags: vec![0; (config.memory_size / cap_size).div_ceil(8)],
+            width: config.width,
+            format: config.cap_format(),
+            log: None,
+        })
     }
 
-    unsafe fn checked_ptr<T>(&self, cap: Capability, offset: Int, count: usize) -> Result<*mut T, RuntimeError> {
-        if !cap.valid {
-            return Err(RuntimeError::InvalidCapability(cap))
-        }
+    /// Starts or stops keeping a log of changes to RAM.
+    pub fn record(&mut self, on: bool) {
+        self.log = on.then(Vec::new);
+    }
 
-        let bounds = cap.inner.bounds();
-        let bounds_usize = bounds.start as usize .. bounds.end as usize;
-        let start = cap.inner.ptr() + offset;
-        let end = start as usize + size_of::<T>() * count;
+    /// The changes logged since the last call.
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        self.log.as_mut().map(std::mem::take).unwrap_or_default()
+    }
 
-        if !bounds.contains(&start) || !bounds_usize.contains(&(end - 1)) || end > MEMORY_SIZE {
-            return Err(RuntimeError::OutOfBoundsAccess(cap))
+    /// Puts back what `change` overwrote, without logging it.
+    fn revert(&mut self, change: &Change) {
+        match change {
+            Change::Bytes { addr, old, .. } => self.mem[*addr..*addr + old.len()].copy_from_slice(old),
+            Change::Tag { addr, valid } => {
+                let idx = addr / self.cap_size();
+                if *valid {
+                    self.cap_tags[idx / 8] &= !(1 << (idx % 8));
+                } else {
+                    self.cap_tags[idx / 8] |= 1 << (idx % 8);
+                }
+            },
         }
+    }
 
-        let ptr = self.get_mut_ptr(cap.inner.ptr() + offset, count)?;
+    pub fn ram_size(&self) -> usize {
+        self.mem.len()
+    }
 
-        Ok(ptr)
+    /// The end of the physical address space: RAM, then device windows.
+    pub fn size(&self) -> usize {
+        self.bus.end().unwrap_or(0).max(self.mem.len())
     }
 
-    pub fn load<T: Copy>(&self, cap: Capability, offset: Int) -> Result<T, RuntimeError> {
-        unsafe {
-            if !cap.inner.perms().read {
-                return Err(RuntimeError::InsufficientPermissions(cap))
-            }
+    pub fn width(&self) -> Width {
+        self.width
+    }
 
-            let ptr = self.checked_ptr(cap, offset, 1)?;
-            let data = *ptr;
+    pub fn format(&self) -> CapFormat {
+        self.format
+    }
 
-            Ok(data)
-        }
+    pub fn cap_size(&self) -> usize {
+        self.format.cap_size(self.width)
     }
 
-    pub fn fetch(&self, cap: Capability, offset: Int) -> Result<Instruction, RuntimeError> {
-        unsafe {
-            if !cap.inner.perms().exec {
-                return Err(RuntimeError::InsufficientPermissions(cap))
-            }
+    /// Checks an access of `len` bytes at `offset` from `cap`'s pointer,
+    /// returning the physical range it covers.
+    fn checked_range(&self, cap: Capability, offset: Int, len: usize, permitted: bool) -> Result<Range<usize>, RuntimeError> {
+        if !cap.valid {
+            return Err(RuntimeError::InvalidCapability(cap))
+        }
 
-            let ptr = self.checked_ptr(cap, offset, 1)?;
-            let data = *ptr;
+        if cap.is_sealed() {
+            return Err(RuntimeError::SealedCapability(cap))
+        }
 
-            Ok(data)
+        if !permitted {
+            return Err(RuntimeError::InsufficientPermissions(cap))
+        }
+
+        let bounds = cap.inner.bounds();
+        let start = cap.inner.ptr().checked_add(offset);
+        let end = start.and_then(|start| start.checked_add(len as Int));
+
+        match (start, end) {
+            (Some(start), Some(end)) if bounds.start <= start && end <= bounds.end && end as usize <= self.size() =>
+                Ok(start as usize..end as usize),
+            _ => Err(RuntimeError::OutOfBoundsAccess(cap)),
         }
     }
 
-    pub fn store<T>(&mut self, cap: Capability, offset: Int, data: T) -> Result<(), RuntimeError> {
-        unsafe {
-            if !cap.inner.perms().write {
-                return Err(RuntimeError::InsufficientPermissions(cap))
-            }
+    fn check_alignment(range: &Range<usize>, align: usize) -> Result<(), RuntimeError> {
+        if !range.start.is_multiple_of(align) {
+            return Err(RuntimeError::UnalignedAccess { addr: range.start as Int, align: align as Int })
+        }
 
-            let ptr = self.checked_ptr(cap, offset, 1)?;
+        Ok(())
+    }
 
-            self.invalidate_range(cap.inner.ptr() + offset, size_of::<T>());
+    /// Fails unless `range` lies in RAM; devices only take word accesses.
+    fn check_ram(&self, range: &Range<usize>) -> Result<(), RuntimeError> {
+        if range.end > self.mem.len() {
+            return Err(RuntimeError::BusError { addr: range.start as Int })
+        }
+
+        Ok(())
+    }
 
-            *ptr = data;
+    pub fn load(&mut self, cap: Capability, offset: Int) -> Result<Int, RuntimeError> {
+        let range = self.checked_range(cap, offset, self.width.bytes(), cap.inner.perms().read)?;
+        Self::check_alignment(&range, self.width.bytes())?;
 
-            Ok(())
+        if self.check_ram(&range).is_ok() {
+            return Ok(self.width.read(&self.mem[range]))
+        }
+
+        let width = self.width;
+        match self.bus.find(&range) {
+            Some((device, offset)) => Ok(device.load(offset, width)),
+            None => Err(RuntimeError::BusError { addr: range.start as Int }),
         }
     }
 
-    pub fn store_slice<T>(&mut self, cap: Capability, offset: Int, data: &[T]) -> Result<(), RuntimeError> {
-        unsafe {
-            if !cap.inner.perms().write {
-                return Err(RuntimeError::InsufficientPermissions(cap))
-            }
+    pub fn fetch(&self, cap: Capability, offset: Int) -> Result<Instruction, RuntimeError> {
+        let range = self.checked_range(cap, offset, self.width.instr_size(), cap.inner.perms().exec)?;
+        self.check_ram(&range)?;
+        let addr = range.start as Int;
 
-            let ptr = self.checked_ptr(cap, offset, data.len())?;
+        encoding::decode(&self.mem[range], self.width).ok_or(RuntimeError::IllegalInstruction { addr })
+    }
+
+    pub fn store(&mut self, cap: Capability, offset: Int, data: Int) -> Result<(), RuntimeError> {
+        let range = self.checked_range(cap, offset, self.width.bytes(), cap.inner.perms().write)?;
+        Self::check_alignment(&range, self.width.bytes())?;
 
-            let size = data.len() * size_of::<T>();
+        if self.check_ram(&range).is_ok() {
+            let mut bytes = vec![0; self.width.bytes()];
+            self.width.write(data, &mut bytes);
+            self.invalidate_range(range.clone());
+            self.write_ram(range.start, &bytes);
+            return Ok(())
+        }
 
-            self.invalidate_range(cap.inner.ptr() + offset, size);
+        let width = self.width;
+        let (device, offset) = self.bus.find(&range).ok_or(RuntimeError::BusError { addr: range.start as Int })?;
+        device.store(offset, data, width);
 
-            let dest_slice = std::slice::from_raw_parts_mut(ptr as *mut u8, size);
+        Ok(())
+    }
 
-            let src_slice = std::slice::from_raw_parts(data.as_ptr() as *const u8, size);
+    pub fn store_slice(&mut self, cap: Capability, offset: Int, data: &[u8]) -> Result<(), RuntimeError> {
+        let range = self.checked_range(cap, offset, data.len(), cap.inner.perms().write)?;
+        self.check_ram(&range)?;
 
-            dest_slice.copy_from_slice(src_slice);
+        self.invalidate_range(range.clone());
+        self.write_ram(range.start, data);
 
-            Ok(())
-        }
+        Ok(())
     }
 
+    // a capability and its tag must share a single granule, so capability
+    // accesses are aligned to the capability size
+
     pub fn load_cap(&self, cap: Capability, offset: Int) -> Result<Capability, RuntimeError> {
-        unsafe {
-            if !cap.inner.perms().read {
-                return Err(RuntimeError::InsufficientPermissions(cap))
-            }
+        let range = self.checked_range(cap, offset, self.cap_size(), cap.inner.perms().read)?;
+        Self::check_alignment(&range, self.cap_size())?;
+        self.check_ram(&range)?;
 
-            let ptr = self.checked_ptr(cap, offset, 1)?;
-            let inner = *ptr;
-            let valid = self.get_cap_tag(((cap.inner.ptr() + offset) / CAP_SIZE as i16).try_into().unwrap());
+        let valid = self.get_cap_tag(range.start / self.cap_size());
+        let inner = Inner::from_bytes(&self.mem[range], self.format, self.width);
 
-            Ok(Capability { inner, valid })
-        }
+        Ok(Capability { inner, valid })
     }
 
     pub fn store_cap(&mut self, cap: Capability, offset: Int, data: Capability) -> Result<(), RuntimeError> {
-        unsafe {
-            if !cap.inner.perms().write {
-                return Err(RuntimeError::InsufficientPermissions(cap))
-            }
+        let range = self.checked_range(cap, offset, self.cap_size(), cap.inner.perms().write)?;
+        Self::check_alignment(&range, self.cap_size())?;
+        self.check_ram(&range)?;
 
-            let ptr = self.checked_ptr(cap, offset, 1)?;
+        let bytes = data.inner.to_bytes(self.format, self.width);
+        self.write_ram(range.start, &bytes);
+        self.set_cap_tag(range.start / self.cap_size(), data.valid);
 
-            let addr = cap.inner.ptr() + offset;
-
-            self.invalidate_range(addr, size_of::<Inner>());
+        Ok(())
+    }
 
-            self.set_cap_tag((addr / CAP_SIZE as i16).try_into().unwrap(), data.valid);
+    /// Raw view of physical memory for debugging, clamped to its size.
+    pub fn bytes(&self, addr: usize, len: usize) -> &[u8] {
+        let start = addr.min(self.mem.len());
+        &self.mem[start..(start + len).min(self.mem.len())]
+    }
 
-            *ptr = data.inner;
+    /// Whether the granule containing `addr` holds a valid capability.
+    pub fn cap_tag(&self, addr: usize) -> bool {
+        addr < self.mem.len() && self.get_cap_tag(addr / self.cap_size())
+    }
 
-            Ok(())
+    fn write_ram(&mut self, addr: usize, bytes: &[u8]) {
+        let range = addr..addr + bytes.len();
+        if let Some(log) = &mut self.log {
+            log.push(Change::Bytes { addr, old: self.mem[range.clone()].to_vec(), new: bytes.to_vec() });
         }
+        self.mem[range].copy_from_slice(bytes);
     }
 
+    fn set_cap_tag(&mut self, idx: usize, valid: bool) {
+        if self.get_cap_tag(idx) == valid {
+            return
+        }
+        if let Some(log) = &mut self.log {
+            log.push(Change::Tag { addr: idx * self.format.cap_size(self.width), valid });
+        }
 
-    unsafe fn set_cap_tag(&mut self, idx: usize, valid: bool) {
         if valid {
-            self.cap_tags[idx / 8] |= 1 << idx % 8;
+            self.cap_tags[idx / 8] |= 1 << (idx % 8);
         } else {
-            self.cap_tags[idx / 8] &= !(1 << idx % 8);
+            self.cap_tags[idx / 8] &= !(1 << (idx % 8));
         }
     }
 
-    unsafe fn get_cap_tag(&self, idx: usize) -> bool {
-        self.cap_tags[idx / 8] & 1 << idx % 8 != 0
+    fn get_cap_tag(&self, idx: usize) -> bool {
+        self.cap_tags[idx / 8] & (1 << (idx % 8)) != 0
     }
 
-    unsafe fn invalidate_range(&mut self, addr: Int, size: usize) {
-        let start = addr as usize / CAP_SIZE;
-        let end = (addr as usize + size - 1) / CAP_SIZE;
-        // if size is 0, then end = start - 1 and i..=(i-1) is an empty range
-        for i in start..=end {
+    fn invalidate_range(&mut self, range: Range<usize>) {
+        let granules = range.start / self.cap_size()..range.end.div_ceil(self.cap_size());
+        for i in granules {
             self.set_cap_tag(i, false);
         }
     }
 }
 
-impl Default for Memory {
-    fn default() -> Self {
-        Self {
-            mem: [0; MEMORY_SIZE],
-            cap_tags: [0; MEMORY_SIZE / CAP_SIZE / 8],
-        }
-    }
-}
-
 #[derive(Debug)]
 pub enum RuntimeError {
     UnalignedAccess { addr: Int, align: Int },
     OutOfBoundsAccess(Capability),
     InvalidCapability(Capability),
     InsufficientPermissions(Capability),
+    SealedCapability(Capability),
+    SealMismatch(Capability),
+    TrustedStackOverflow,
+    TrustedStackUnderflow,
+    IllegalInstruction { addr: Int },
+    DivideByZero,
+    /// Nothing answers at `addr`, or a device was accessed other than by word
+    BusError { addr: Int },
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::UnalignedAccess { addr, align } =>
+                write!(f, "unaligned access at {addr:04x} (alignment {align})"),
+            RuntimeError::OutOfBoundsAccess(cap) => write!(f, "out of bounds access through {cap:?}"),
+            RuntimeError::InvalidCapability(cap) => write!(f, "invalid capability {cap:?}"),
+            RuntimeError::InsufficientPermissions(cap) => write!(f, "insufficient permissions on {cap:?}"),
+            RuntimeError::SealedCapability(cap) => write!(f, "use of sealed capability {cap:?}"),
+            RuntimeError::SealMismatch(cap) => write!(f, "object type mismatch on {cap:?}"),
+            RuntimeError::TrustedStackOverflow => write!(f, "trusted stack overflow"),
+            RuntimeError::TrustedStackUnderflow => write!(f, "compartment return with no caller"),
+            RuntimeError::IllegalInstruction { addr } => write!(f, "illegal instruction at {addr:04x}"),
+            RuntimeError::DivideByZero => write!(f, "division by zero"),
+            RuntimeError::BusError { addr } => write!(f, "bus error at {addr:04x}"),
+        }
+    }
+}
+
+impl RuntimeError {
+    /// The code a trap handler reads with `Cause`.
+    pub fn cause(&self) -> Int {
+        match self {
+            RuntimeError::UnalignedAccess { .. } => 1,
+            RuntimeError::OutOfBoundsAccess(_) => 2,
+            RuntimeError::InvalidCapability(_) => 3,
+            RuntimeError::InsufficientPermissions(_) => 4,
+            RuntimeError::SealedCapability(_) => 5,
+            RuntimeError::SealMismatch(_) => 6,
+            RuntimeError::TrustedStackOverflow => 7,
+            RuntimeError::TrustedStackUnderflow => 8,
+            RuntimeError::IllegalInstruction { .. } => 9,
+            RuntimeError::DivideByZero => 10,
+            RuntimeError::BusError { .. } => 11,
+        }
+    }
+
+    pub fn capability(&self) -> Option<Capability> {
+        match self {
+            RuntimeError::OutOfBoundsAccess(cap)
+            | RuntimeError::InvalidCapability(cap)
+            | RuntimeError::InsufficientPermissions(cap)
+            | RuntimeError::SealedCapability(cap)
+            | RuntimeError::SealMismatch(cap) => Some(*cap),
+            _ => None,
+        }
+    }
 }
 
 impl Machine {
-    pub fn new() -> Self {
-        let mut mach = Self { memory: Default::default(), reg: Default::default() };
+    /// A machine with the standard devices, on a console with no input.
+    pub fn new(config: Config) -> Result<Self, ConfigError> {
+        let console = console::handle(StdoutConsole::new(io::empty()));
+        Self::with_devices(config, device::standard(console.clone()), console)
+    }
+
+    /// The root capabilities span RAM and every device window.
+    pub fn with_devices(config: Config, devices: Vec<Box<dyn Device>>, console: console::Handle) -> Result<Self, ConfigError> {
+        let memory = Memory::new(config, devices)?;
+        let format = config.cap_format();
+        let size = memory.size();
+        let reg = RegisterFile { width: config.width, ..Default::default() };
+        let mut mach = Self { memory, reg, trusted_stack: Default::default(), halted: None, ticks: 0, console, tracer: None, history: None };
         let cap = Capability {
-            inner: Inner::new(0, 0..MEMORY_SIZE as Int, Permissions::rwx(true, true, true), Seal::Unsealed),
+            inner: Inner::new(0, format.round(0..size as Int), Permissions::root(), Seal::Unsealed),
             valid: true,
         };
         mach.reg[CRegister::CC] = cap; 
         mach.reg[CRegister::DD] = cap; 
-        mach
+
+        // authority over every object type, selected by the pointer
+        mach.reg[CRegister::C0] = Capability {
+            inner: Inner::new(1, format.round(1..(u8::MAX as Int + 1)), Permissions::sealing(true, true), Seal::Unsealed),
+            valid: true,
+        };
+        Ok(mach)
+    }
+
+    pub fn width(&self) -> Width {
+        self.memory.width()
+    }
+
+    /// Records an entry for every tick from now on.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+        self.memory.record(true);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        let tracer = self.tracer.take();
+        self.memory.record(self.history.is_some());
+        tracer
+    }
+
+    /// Keeps enough of the last `limit` ticks to undo them with `step_back`.
+    /// Devices and the console aren't rewound.
+    pub fn record_history(&mut self, limit: usize) {
+        self.history = Some(History { undo: VecDeque::new(), limit });
+        self.memory.record(true);
+    }
+
+    /// Ticks that `step_back` can still undo.
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |history| history.undo.len())
     }
 
+    /// Undoes the last tick that completed, returning false once history
+    /// runs out.
+    pub fn step_back(&mut self) -> bool {
+        let Some(undo) = self.history.as_mut().and_then(|history| history.undo.pop_back()) else {
+            return false
+        };
+
+        for change in undo.changes.iter().rev() {
+            self.memory.revert(change);
+        }
+        self.reg = undo.reg;
+        self.trusted_stack = undo.trusted_stack;
+        self.halted = undo.halted;
+        self.ticks = undo.ticks;
+        true
+    }
+
+    /// Replaces the console for `Emit`, `Input` and the UART.
+    pub fn set_console(&mut self, console: impl Console + 'static) {
+        *self.console.borrow_mut() = Box::new(console);
+    }
+
+    pub fn next_instruction(&self) -> Result<Instruction, RuntimeError> {
+        self.memory.fetch(self.reg[CRegister::CC], self.reg.pc)
+    }
+
+    /// Runs until the program halts or faults, or `max_ticks` have passed.
+    pub fn run(&mut self, max_ticks: Option<u64>) -> RunOutcome {
+        let mut ticks = 0;
+        while max_ticks.is_none_or(|max| ticks < max) {
+            if let Some(code) = self.halted {
+                return RunOutcome::Halted(code)
+            }
+            if let Err(error) = self.tick() {
+                return RunOutcome::Faulted { error, pc: self.reg.pc }
+            }
+            ticks += 1;
+        }
+
+        match self.halted {
+            Some(code) => RunOutcome::Halted(code),
+            None => RunOutcome::Exhausted,
+        }
+    }
+
+    /// Executes a single instruction. Does nothing once the machine has halted.
+    ///
+    /// A fault is delivered to the trap handler in `Tcc` if one is installed,
+    /// and only escapes to the host when there is none or the handler itself
+    /// faults. Once the instruction retires, a pending device interrupt
+    /// enters the handler in `Ivec` if interrupts are enabled.
     pub fn tick(&mut self) -> Result<(), RuntimeError> {
-        let instr = self.memory.fetch(self.reg[CRegister::CC], self.reg.pc)?;
+        if self.halted.is_some() {
+            return Ok(())
+        }
+
+        let reg = (self.tracer.is_some() || self.history.is_some()).then(|| self.reg.clone());
+        let instr = self.tracer.as_ref().and_then(|_| self.next_instruction().ok());
+        let trusted_stack = self.history.as_ref().map(|_| self.trusted_stack.clone());
+        let (halted, ticks) = (self.halted, self.ticks);
+
+        let res = self.advance();
+        let changes = self.memory.take_changes();
+
+        if let (Some(reg), Some(tracer)) = (&reg, &mut self.tracer) {
+            let event = match &res {
+                Ok(event) => *event,
+                Err(error) => Some(Event::Fault { cause: error.cause() }),
+            };
+            tracer.record(&Entry {
+                tick: self.ticks,
+                pc: reg.pc,
+                instr,
+                regs: self.reg.diff(reg),
+                changes: changes.clone(),
+                event,
+            });
+        }
+
+        // a fault that escapes leaves the machine where it was, so there's
+        // nothing to undo
+        if let (Some(reg), Some(trusted_stack), Some(history), Ok(_)) = (reg, trusted_stack, &mut self.history, &res) {
+            if history.undo.len() == history.limit {
+                history.undo.pop_front();
+            }
+            history.undo.push_back(Undo { reg, trusted_stack, halted, ticks, changes });
+        }
+
+        self.ticks += 1;
+        res.map(|_| ())
+    }
+
+    fn advance(&mut self) -> Result<Option<Event>, RuntimeError> {
+        let mut event = None;
+
+        self.memory.bus.tick();
+        match self.step() {
+            Err(error) if self.reg[SpecialRegister::Tcc].valid && !self.reg.in_trap => {
+                event = Some(Event::Trap { cause: error.cause() });
+                self.trap(error);
+            },
+            res => res?,
+        }
+
+        if let Some(code) = self.halted {
+            return Ok(Some(Event::Halt { code }))
+        }
+
+        if self.reg.interrupts && self.reg[SpecialRegister::Ivec].valid {
+            if let Some(irq) = self.memory.bus.interrupt() {
+                event = Some(Event::Interrupt { irq });
+                self.interrupt(irq);
+            }
+        }
+
+        Ok(event)
+    }
+
+    fn step(&mut self) -> Result<(), RuntimeError> {
+        let instr = self.next_instruction()?;
 
         if !self.execute_instruction(instr)? {
-            self.reg.pc += size_of::<Instruction>() as Int;
+            self.reg.pc += self.width().instr_size() as Int;
+        }
+
+        Ok(())
+    }
+
+    fn trap(&mut self, error: RuntimeError) {
+        use SpecialRegister::*;
+
+        let cc = self.reg[CRegister::CC];
+        self.reg[Epcc] = cc.set_addr(cc.inner.ptr() + self.reg.pc, self.memory.format());
+        self.reg[Ecap] = error.capability().unwrap_or_default();
+        self.reg.cause = error.cause();
+        self.reg.in_trap = true;
+
+        self.reg[CRegister::CC] = self.reg[Tcc];
+        self.reg.pc = 0;
+    }
+
+    /// Saves `CC`, `DD` and the PC and enters vector `irq` with interrupts
+    /// disabled, all in one step, so the interrupted code can be resumed
+    /// exactly with `IRet`.
+    fn interrupt(&mut self, irq: usize) {
+        use SpecialRegister::*;
+
+        self.reg[Ipcc] = self.reg[CRegister::CC];
+        self.reg[Ipdd] = self.reg[CRegister::DD];
+        self.reg.ipc = self.reg.pc;
+        self.reg.interrupts = false;
+
+        self.reg[CRegister::CC] = self.reg[Ivec];
+        self.reg[CRegister::DD] = self.reg[Idd];
+        self.reg.pc = (irq * self.width().instr_size()) as Int;
+    }
+
+    /// Interrupt and trap state belongs to code running under a privileged `CC`.
+    fn check_privileged(&self) -> Result<(), RuntimeError> {
+        let cc = self.reg[CRegister::CC];
+        if !cc.inner.perms().privileged {
+            return Err(RuntimeError::InsufficientPermissions(cc))
         }
 
         Ok(())
@@ -264,10 +760,16 @@ impl Machine {
         match instr {
             Mov(a, b) => self.reg[a] = self.eval(b),
 
-            Add(a, b) => self.reg[a] = self.reg[a].saturating_add(self.eval(b)),
-            Sub(a, b) => self.reg[a] = self.reg[a].saturating_sub(self.eval(b)),
-            Mul(a, b) => self.reg[a] = self.reg[a].saturating_mul(self.eval(b)),
-            Div(a, b) => self.reg[a] = self.reg[a].saturating_div(self.eval(b)),
+            Add(a, b) => self.reg[a] = self.width().clamp(self.reg[a].saturating_add(self.eval(b))),
+            Sub(a, b) => self.reg[a] = self.width().clamp(self.reg[a].saturating_sub(self.eval(b))),
+            Mul(a, b) => self.reg[a] = self.width().clamp(self.reg[a].saturating_mul(self.eval(b))),
+            Div(a, b) => {
+                let divisor = self.eval(b);
+                if divisor == 0 {
+                    return Err(RuntimeError::DivideByZero)
+                }
+                self.reg[a] = self.width().clamp(self.reg[a].saturating_div(divisor))
+            },
 
             And(a, b) => self.reg[a] &= self.eval(b),
             Or(a, b) => self.reg[a] |= self.eval(b),
@@ -277,7 +779,7 @@ impl Machine {
             Load(dest, src, offset) =>
                 self.reg[dest] = self.memory.load(self.reg[src], self.eval(offset))?,
             Store(dest, offset, src) =>
-                self.memory.store(self.reg[dest], self.eval(src), self.eval(src))?,
+                self.memory.store(self.reg[dest], self.eval(offset), self.eval(src))?,
 
             Jmp(target, offset) => {
                 self.reg[CC] = self.reg[target];
@@ -286,35 +788,293 @@ impl Machine {
             },
 
             Push(a) => {
-                let addr = self.reg[GpRegister::SP].saturating_sub(size_of::<Int>() as Int);
+                let addr = self.reg[GpRegister::SP].saturating_sub(self.width().bytes() as Int);
                 self.memory.store(self.reg[DD], addr, self.eval(a))?;
                 self.reg[GpRegister::SP] = addr;
             },
 
             Pop(a) => {
                 self.reg[a] = self.memory.load(self.reg[DD], self.reg[GpRegister::SP])?;
-                self.reg[GpRegister::SP] = self.reg[GpRegister::SP].saturating_add(size_of::<Int>() as Int);
+                self.reg[GpRegister::SP] = self.reg[GpRegister::SP].saturating_add(self.width().bytes() as Int);
             },
 
             Cond(a, c, b) => {
                 if !c.test(self.reg[a], self.eval(b)) {
-                    self.reg.pc += 2 * size_of::<Instruction>() as Int;
+                    self.reg.pc += 2 * self.width().instr_size() as Int;
                 }
             }
 
             Emit(a) => {
                 let n = self.eval(a) % 256;
-                print!("{}", n as u8 as char)
+                self.console.borrow_mut().write(n as u8)
+            },
+            Input(a) => self.reg[a] = self.console.borrow_mut().read().map_or(-1, Int::from),
+
+            Halt(a) => {
+                self.halted = Some(self.eval(a));
+                return Ok(true)
+            },
+
+            CReadSpecial(dest, src) => {
+                self.check_privileged()?;
+                self.reg[dest] = self.reg[src]
+            },
+            CWriteSpecial(dest, src) => {
+                self.check_privileged()?;
+                self.reg[dest] = self.reg[src]
+            },
+            Cause(a) => self.reg[a] = self.reg.cause,
+
+            TRet => {
+                self.check_privileged()?;
+                self.reg[CC] = self.reg[SpecialRegister::Epcc];
+                self.reg.pc = 0;
+                self.reg.in_trap = false;
+                return Ok(true)
+            },
+
+            Ei => {
+                self.check_privileged()?;
+                self.reg.interrupts = true;
+            },
+            Di => {
+                self.check_privileged()?;
+                self.reg.interrupts = false;
+            },
+            IRet => {
+                self.check_privileged()?;
+                self.reg[CC] = self.reg[SpecialRegister::Ipcc];
+                self.reg[DD] = self.reg[SpecialRegister::Ipdd];
+                self.reg.pc = self.reg.ipc;
+                self.reg.interrupts = true;
+                return Ok(true)
+            },
+            IpcRead(a) => {
+                self.check_privileged()?;
+                self.reg[a] = self.reg.ipc
+            },
+            IpcWrite(a) => {
+                self.check_privileged()?;
+                self.reg.ipc = self.eval(a)
+            },
+
+            CLoad(dest, src) => self.reg[dest] = self.reg[src].inner.ptr(),
+            CStore(src, dest) => self.reg[dest] = self.reg[dest].set_addr(self.eval(src), self.memory.format()),
+
+            CLoadCap(dest, src, offset) =>
+                self.reg[dest] = self.memory.load_cap(self.reg[src], self.eval(offset))?,
+            CStoreCap(dest, offset, src) =>
+                self.memory.store_cap(self.reg[dest], self.eval(offset), self.reg[src])?,
+
+            CJmp(target) => {
+                self.reg[CC] = self.reg[target];
+                self.reg.pc = 0;
+                return Ok(true)
+            },
+
+            CPushCap(a) => {
+                let addr = self.reg[GpRegister::SP].saturating_sub(self.memory.cap_size() as Int);
+                self.memory.store_cap(self.reg[DD], addr, self.reg[a])?;
+                self.reg[GpRegister::SP] = addr;
+            },
+
+            CPopCap(a) => {
+                self.reg[a] = self.memory.load_cap(self.reg[DD], self.reg[GpRegister::SP])?;
+                self.reg[GpRegister::SP] = self.reg[GpRegister::SP].saturating_add(self.memory.cap_size() as Int);
+            },
+
+            CInvoke(code, data) => {
+                let (code, data) = self.unseal_pair(self.reg[code], self.reg[data])?;
+                self.reg[CC] = code;
+                self.reg[DD] = data;
+                self.reg.pc = 0;
+                return Ok(true)
+            },
+
+            CCall(code, data) => {
+                let (code, data) = self.unseal_pair(self.reg[code], self.reg[data])?;
+                self.trusted_stack.push(Frame {
+                    cap: self.reg.cap,
+                    saved: [self.reg[GpRegister::R4], self.reg[GpRegister::R5], self.reg[GpRegister::R6]],
+                    sp: self.reg[GpRegister::SP],
+                    pc: self.reg.pc + self.width().instr_size() as Int,
+                })?;
+
+                // only the arguments cross over: the caller's other
+                // capabilities, C0's sealing authority among them, and its
+                // DD are only reachable from the trusted stack until it
+                // returns. The callee runs on a stack at the top of its own
+                // data capability
+                let args = (self.reg[CRegister::C2], self.reg[CRegister::C3]);
+                self.reg.cap = Default::default();
+                (self.reg[CRegister::C2], self.reg[CRegister::C3]) = args;
+                for r in [GpRegister::R4, GpRegister::R5, GpRegister::R6] {
+                    self.reg[r] = 0;
+                }
+                self.reg[CC] = code;
+                self.reg[DD] = data;
+                self.reg[GpRegister::SP] = data.inner.bounds().end - data.inner.ptr();
+                self.reg.pc = 0;
+                return Ok(true)
+            },
+
+            CReturn => {
+                // results come back the way arguments went in; whatever
+                // else the callee left behind is overwritten by the caller's
+                let frame = self.trusted_stack.pop()?;
+                let results = (self.reg[CRegister::C2], self.reg[CRegister::C3]);
+                self.reg.cap = frame.cap;
+                (self.reg[CRegister::C2], self.reg[CRegister::C3]) = results;
+                [self.reg[GpRegister::R4], self.reg[GpRegister::R5], self.reg[GpRegister::R6]] = frame.saved;
+                self.reg[GpRegister::SP] = frame.sp;
+                self.reg.pc = frame.pc;
+                return Ok(true)
+            },
+
+            CSeal(a, auth) => {
+                let otype = self.seal_authority(self.reg[auth], self.reg[auth].inner.perms().seal)?;
+                let cap = self.reg[a];
+                if !cap.valid {
+                    return Err(RuntimeError::InvalidCapability(cap))
+                }
+                if cap.is_sealed() {
+                    return Err(RuntimeError::SealedCapability(cap))
+                }
+                self.reg[a] = cap.sealed(otype);
+            },
+
+            CUnseal(a, auth) => {
+                let otype = self.seal_authority(self.reg[auth], self.reg[auth].inner.perms().unseal)?;
+                let cap = self.reg[a];
+                if !cap.valid {
+                    return Err(RuntimeError::InvalidCapability(cap))
+                }
+                match cap.inner.seal() {
+                    Seal::Sealed(t) if t == otype => self.reg[a] = cap.unsealed(),
+                    _ => return Err(RuntimeError::SealMismatch(cap)),
+                }
+            },
+
+            CSetBounds(dest, src, len) => self.reg[dest] = self.reg[src].set_bounds(self.eval(len), self.memory.format()),
+            CAndPerm(dest, src, mask) => self.reg[dest] = self.reg[src].and_perms(self.eval(mask) as u8),
+            CIncOffset(dest, src, delta) => {
+                let addr = self.width().clamp(self.reg[src].inner.ptr().saturating_add(self.eval(delta)));
+                self.reg[dest] = self.reg[src].set_addr(addr, self.memory.format());
             },
         }
 
         Ok(false)
     }
 
+    /// Checks that `auth` may seal or unseal, returning the object type it
+    /// selects with its pointer.
+    fn seal_authority(&self, auth: Capability, permitted: bool) -> Result<NonZeroU8, RuntimeError> {
+        if !auth.valid {
+            return Err(RuntimeError::InvalidCapability(auth))
+        }
+        if auth.is_sealed() {
+            return Err(RuntimeError::SealedCapability(auth))
+        }
+        if !permitted {
+            return Err(RuntimeError::InsufficientPermissions(auth))
+        }
+
+        u8::try_from(auth.inner.ptr()).ok()
+            .and_then(NonZeroU8::new)
+            .filter(|_| auth.inner.in_range())
+            .ok_or(RuntimeError::OutOfBoundsAccess(auth))
+    }
+
+    /// Unseals a code/data pair sharing one object type, as `CInvoke` does.
+    fn unseal_pair(&self, code: Capability, data: Capability) -> Result<(Capability, Capability), RuntimeError> {
+        for cap in [code, data] {
+            if !cap.valid {
+                return Err(RuntimeError::InvalidCapability(cap))
+            }
+        }
+
+        match (code.inner.seal(), data.inner.seal()) {
+            (Seal::Sealed(a), Seal::Sealed(b)) if a == b => (),
+            (Seal::Sealed(_), _) => return Err(RuntimeError::SealMismatch(data)),
+            _ => return Err(RuntimeError::SealMismatch(code)),
+        }
+
+        if !code.inner.perms().exec {
+            return Err(RuntimeError::InsufficientPermissions(code))
+        }
+
+        Ok((code.unsealed(), data.unsealed()))
+    }
+
     fn eval(&self, value: Value) -> Int {
         match value {
             Value::Reg(gp) => self.reg[gp],
             Value::Imm(imm) => imm,
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, path::Path, rc::Rc};
+
+    use super::*;
+    use crate::{compile, console::BufferConsole, parse, preprocess};
+
+    /// A machine with `source` loaded at 0 under the root, and what it
+    /// writes to its console.
+    fn machine(source: &str) -> (Machine, Rc<RefCell<Vec<u8>>>) {
+        let lines = preprocess::expand(source, Path::new("test.s")).unwrap();
+        let image = compile::compile(parse::parse(&lines).unwrap(), Width::W16).unwrap();
+
+        let mut machine = Machine::new(Config::default()).unwrap();
+        let root = machine.reg[CRegister::DD];
+        machine.memory.store_slice(root, 0, &image.bytes).unwrap();
+
+        let console = BufferConsole::new(b"");
+        let output = console.output();
+        machine.set_console(console);
+        (machine, output)
+    }
+
+    #[test]
+    fn runs_until_halt() {
+        let (mut machine, output) = machine("
+            mov r0 'h'
+            emit r0
+            emit 'i'
+            halt 3
+        ");
+        assert!(matches!(machine.run(None), RunOutcome::Halted(3)));
+        assert_eq!(output.borrow().as_slice(), b"hi");
+        assert_eq!(machine.ticks, 4);
+    }
+
+    #[test]
+    fn reports_a_fault_and_where() {
+        let (mut machine, output) = machine("
+            emit 'a'
+            csetbounds c2 dd 4
+            load r0 c2 8
+            emit 'b'
+            halt 0
+        ");
+        match machine.run(None) {
+            RunOutcome::Faulted { error: RuntimeError::OutOfBoundsAccess(_), pc } =>
+                assert_eq!(pc, 2 * Width::W16.instr_size() as Int),
+            outcome => panic!("{outcome:?}"),
+        }
+        assert_eq!(output.borrow().as_slice(), b"a");
+    }
+
+    #[test]
+    fn stops_when_the_budget_runs_out() {
+        let (mut machine, output) = machine("
+        loop:
+            emit '.'
+            jmp cc #loop
+        ");
+        assert!(matches!(machine.run(Some(10)), RunOutcome::Exhausted));
+        assert_eq!(output.borrow().as_slice(), b".....");
+        assert!(machine.halted.is_none());
+    }
+}